@@ -1,12 +1,28 @@
 use std::env;
 
+use crate::{
+    crypto::{CipherSuite, CryptoConfig, KdfParams, MAX_KDF_PARAMS, Padding, keyring::Keyring},
+    store::StoreKind,
+};
+
 /// Configuration values loaded from environment variables.
 ///
 /// Holds runtime configuration for the application.
 pub struct AppConfig {
     pub region: String,
     pub db_url: String,
+    pub db_table: String,
+    pub store: StoreKind,
+    pub sqlite_path: String,
+    pub redis_url: String,
+    /// Directory the chunks of file attachments are kept in. Only server
+    /// mode serves files, Lambda has no disk that outlives an instance.
+    pub blob_path: String,
     pub server_port: u16,
+    /// Whether /decrypt/{id}/{key} is served. The key ends up in
+    /// access logs and browser history there.
+    pub legacy_key_path: bool,
+    pub crypto: CryptoConfig,
 }
 
 impl AppConfig {
@@ -17,15 +33,99 @@ impl AppConfig {
     pub fn from_env() -> Self {
         let region = env::var("CONFIG_REGION").expect("unable to get CONFIG_REGION");
         let db_url = env::var("CONFIG_DB_URL").expect("unable to get CONFIG_DB_URL");
+        let db_table = env::var("CONFIG_DB_TABLE").unwrap_or_else(|_| "encryptData".to_string());
+
+        // Where records are kept, DynamoDB unless set.
+        let store = match env::var("CONFIG_STORE") {
+            Ok(name) => StoreKind::from_name(&name)
+                .unwrap_or_else(|| panic!("CONFIG_STORE '{}' is not supported", name)),
+            Err(_) => StoreKind::default(),
+        };
+        let sqlite_path =
+            env::var("CONFIG_SQLITE_PATH").unwrap_or_else(|_| "cipherlink.db".to_string());
+        let redis_url =
+            env::var("CONFIG_REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+        let blob_path = env::var("CONFIG_BLOB_PATH").unwrap_or_else(|_| "blobs".to_string());
         let server_port = env::var("CONFIG_SERVER_PORT")
             .expect("unable to get CONFIG_SERVER_PORT")
             .parse::<u16>()
             .expect("CONFIG_SERVER_PORT must be a valid u16");
 
+        // The key-in-path form stays on until clients moved off it.
+        let legacy_key_path = match env::var("CONFIG_LEGACY_KEY_PATH") {
+            Ok(val) => val
+                .parse::<bool>()
+                .expect("CONFIG_LEGACY_KEY_PATH must be true or false"),
+            Err(_) => true,
+        };
+
+        // Argon2id costs are optional, the argon2 defaults are used otherwise.
+        let defaults = KdfParams::default();
+        let kdf_params = KdfParams {
+            m_cost: optional_u32("CONFIG_KDF_M_COST").unwrap_or(defaults.m_cost),
+            t_cost: optional_u32("CONFIG_KDF_T_COST").unwrap_or(defaults.t_cost),
+            p_cost: optional_u32("CONFIG_KDF_P_COST").unwrap_or(defaults.p_cost),
+        }
+        .check()
+        .unwrap_or_else(|_| {
+            panic!(
+                "CONFIG_KDF_M_COST, _T_COST and _P_COST must be at most {}, {} and {}",
+                MAX_KDF_PARAMS.m_cost, MAX_KDF_PARAMS.t_cost, MAX_KDF_PARAMS.p_cost
+            )
+        });
+
+        // Master keys are optional, records are not wrapped without them.
+        let keyring = match env::var("CONFIG_MASTER_KEYS") {
+            Ok(spec) => {
+                let active = env::var("CONFIG_ACTIVE_MASTER_KEY")
+                    .expect("unable to get CONFIG_ACTIVE_MASTER_KEY");
+                Keyring::from_spec(&spec, &active)
+                    .unwrap_or_else(|e| panic!("CONFIG_MASTER_KEYS is invalid: {}", e))
+            }
+            Err(_) => Keyring::default(),
+        };
+
+        // Suite new records are sealed with. Each record stores its own.
+        let suite = match env::var("CONFIG_CIPHER_SUITE") {
+            Ok(name) => CipherSuite::from_name(&name)
+                .unwrap_or_else(|| panic!("CONFIG_CIPHER_SUITE '{}' is not supported", name)),
+            Err(_) => CipherSuite::default(),
+        };
+
+        // Padding for new records. Each record stores its own scheme.
+        let padding = match env::var("CONFIG_PADDING") {
+            Ok(name) => Padding::from_name(&name)
+                .unwrap_or_else(|| panic!("CONFIG_PADDING '{}' is not supported", name)),
+            Err(_) => Padding::default(),
+        };
+
         AppConfig {
             region,
             db_url,
+            db_table,
+            store,
+            sqlite_path,
+            redis_url,
+            blob_path,
             server_port,
+            legacy_key_path,
+            crypto: CryptoConfig {
+                kdf_params,
+                keyring,
+                suite,
+                padding,
+            },
         }
     }
 }
+
+/// Reads an optional numeric environment variable.
+///
+/// # Panics
+/// Panics if the variable is set but is not a valid u32.
+fn optional_u32(name: &str) -> Option<u32> {
+    env::var(name).ok().map(|val| {
+        val.parse::<u32>()
+            .unwrap_or_else(|_| panic!("{} must be a valid u32", name))
+    })
+}
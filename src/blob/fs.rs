@@ -0,0 +1,91 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use async_trait::async_trait;
+
+use crate::{blob::BlobStore, error::CipherLinkError};
+
+/// Keeps chunks as files on the local filesystem, one directory per
+/// file under `root`.
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        FsBlobStore {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The directory the chunks of `id` are kept in. Ids come from
+    /// request paths, so anything but a UUID is refused rather than
+    /// joined onto `root`.
+    fn dir(&self, id: &str) -> Result<PathBuf, CipherLinkError> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(CipherLinkError::not_found(id));
+        }
+        Ok(self.root.join(id))
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsBlobStore {
+    async fn put(&self, id: &str, index: u32, chunk: Vec<u8>) -> Result<(), CipherLinkError> {
+        let dir = self.dir(id)?;
+        tokio::fs::create_dir_all(&dir).await.map_err(unavailable)?;
+        tokio::fs::write(dir.join(index.to_string()), chunk)
+            .await
+            .map_err(unavailable)
+    }
+
+    async fn get(&self, id: &str, index: u32) -> Result<Vec<u8>, CipherLinkError> {
+        match tokio::fs::read(self.dir(id)?.join(index.to_string())).await {
+            Ok(chunk) => Ok(chunk),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(CipherLinkError::not_found(id)),
+            Err(e) => Err(unavailable(e)),
+        }
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), CipherLinkError> {
+        match tokio::fs::remove_dir_all(self.dir(id)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(unavailable(e)),
+        }
+    }
+
+    async fn list(&self, before: u64) -> Result<Vec<String>, CipherLinkError> {
+        let mut dirs = match tokio::fs::read_dir(&self.root).await {
+            Ok(dirs) => dirs,
+            // Nothing was uploaded yet.
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(unavailable(e)),
+        };
+        let mut ids = Vec::new();
+        while let Some(dir) = dirs.next_entry().await.map_err(unavailable)? {
+            let metadata = dir.metadata().await.map_err(unavailable)?;
+            // Adding a chunk file updates the directory's mtime.
+            let written = metadata
+                .modified()
+                .map_err(unavailable)?
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            if metadata.is_dir()
+                && written < before
+                && let Some(id) = dir.file_name().to_str()
+            {
+                ids.push(id.to_string());
+            }
+        }
+        Ok(ids)
+    }
+}
+
+fn unavailable(e: std::io::Error) -> CipherLinkError {
+    CipherLinkError::Unavailable(format!("Blob store error: {}", e))
+}
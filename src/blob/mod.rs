@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{app_config::AppConfig, error::CipherLinkError};
+
+mod fs;
+
+pub use fs::FsBlobStore;
+
+/// Where the sealed chunks of file attachments live. They don't fit
+/// DynamoDB's item size limit, so the record only holds the key to
+/// them and the chunks are kept here under the record's id.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Stores chunk `index` of the file under `id`.
+    ///
+    /// # Errors
+    /// Errors if the backend write fails.
+    async fn put(&self, id: &str, index: u32, chunk: Vec<u8>) -> Result<(), CipherLinkError>;
+
+    /// Returns chunk `index` of the file under `id`.
+    ///
+    /// # Errors
+    /// Errors with `CipherLinkError::NotFound` if there is no such
+    /// chunk, or if the backend read fails.
+    async fn get(&self, id: &str, index: u32) -> Result<Vec<u8>, CipherLinkError>;
+
+    /// Removes every chunk of the file under `id`. A file that is
+    /// already gone is not an error.
+    ///
+    /// # Errors
+    /// Errors if the backend delete fails.
+    async fn delete(&self, id: &str) -> Result<(), CipherLinkError>;
+
+    /// Returns the ids of files whose chunks were last written before
+    /// unix time `before`, so uploads still in progress can be told
+    /// apart from files left behind.
+    ///
+    /// # Errors
+    /// Errors if the backend read fails.
+    async fn list(&self, before: u64) -> Result<Vec<String>, CipherLinkError>;
+}
+
+/// Builds the blob store described by the config.
+pub fn init(config: &AppConfig) -> Arc<dyn BlobStore> {
+    Arc::new(FsBlobStore::new(&config.blob_path))
+}
@@ -0,0 +1,330 @@
+use crate::crypto::{CipherSuite, CryptoError, EncryptData, Kdf, KdfParams, Padding, WrappedKey};
+
+/// Current version of the serialized envelope. Version 5 records the
+/// padding scheme applied to the plaintext.
+pub const ENVELOPE_VERSION: u8 = 5;
+
+/// Adds a key commitment so the ciphertext only opens under one key.
+pub const ENVELOPE_V4: u8 = 4;
+
+/// Adds the master key section holding the wrapped data key.
+pub const ENVELOPE_V3: u8 = 3;
+
+/// Binds the record id and envelope header as AEAD associated data.
+pub const ENVELOPE_V2: u8 = 2;
+
+/// First envelope version, sealed without associated data. Same layout
+/// as version 2.
+pub const ENVELOPE_V1: u8 = 1;
+
+/// Version assigned to records that predate the envelope and were
+/// stored as loose attributes. It is never written to bytes.
+pub const LEGACY_VERSION: u8 = 0;
+
+const SUITE_AES_256_GCM: u8 = 1;
+const SUITE_CHACHA20_POLY1305: u8 = 2;
+const SUITE_XCHACHA20_POLY1305: u8 = 3;
+const SUITE_AES_256_GCM_SIV: u8 = 4;
+
+const KDF_SHA256: u8 = 0;
+const KDF_ARGON2ID: u8 = 1;
+const KDF_X25519: u8 = 2;
+const KDF_SHAMIR: u8 = 3;
+
+const PADDING_NONE: u8 = 0;
+const PADDING_BUCKETS: u8 = 1;
+const PADDING_PADME: u8 = 2;
+
+/// Serializes the data into a self-describing envelope:
+///
+/// | field       | size                            |
+/// |-------------|---------------------------------|
+/// | version     | 1                               |
+/// | suite id    | 1                               |
+/// | kdf id      | 1                               |
+/// | kdf params  | 12 for argon2id (m, t, p as BE) |
+/// |             | 2 for shamir (k, n)             |
+/// | salt len    | 1                               |
+/// | salt        | salt len                        |
+/// | nonce len   | 1                               |
+/// | nonce       | nonce len                       |
+/// | commit len  | 1, version 4 and up             |
+/// | commitment  | commit len                      |
+/// | padding id  | 1, version 5 and up             |
+/// | key id len  | 1, version 3 and up             |
+/// | key id      | key id len, 0 if not wrapped    |
+/// | wrapped len | 1, version 3 and up             |
+/// | wrapped key | wrapped len                     |
+/// | ciphertext  | remainder                       |
+///
+/// The master key section is left out of the associated data so
+/// records can be re-wrapped without the user key. Recipient stanzas
+/// are not part of the envelope and are stored beside it.
+pub fn to_bytes(data: &EncryptData) -> Vec<u8> {
+    let mut out = header_bytes(data);
+    if data.version >= ENVELOPE_V3 {
+        // key ids and wrapped keys are bounded by the keyring.
+        match &data.wrapped_key {
+            Some(wrapped_key) => {
+                out.push(wrapped_key.key_id.len() as u8);
+                out.extend_from_slice(wrapped_key.key_id.as_bytes());
+                out.push(wrapped_key.wrapped.len() as u8);
+                out.extend_from_slice(&wrapped_key.wrapped);
+            }
+            None => out.extend_from_slice(&[0, 0]),
+        }
+    }
+    out.extend_from_slice(&data.encrypted_text);
+    out
+}
+
+/// Serializes the envelope fields up to and including the padding id.
+/// This is what gets authenticated as associated data.
+pub fn header_bytes(data: &EncryptData) -> Vec<u8> {
+    let mut out =
+        Vec::with_capacity(3 + 12 + 3 + data.salt.len() + data.nonce.len() + data.commitment.len());
+    out.push(data.version);
+    out.push(match data.suite {
+        CipherSuite::Aes256Gcm => SUITE_AES_256_GCM,
+        CipherSuite::ChaCha20Poly1305 => SUITE_CHACHA20_POLY1305,
+        CipherSuite::XChaCha20Poly1305 => SUITE_XCHACHA20_POLY1305,
+        CipherSuite::Aes256GcmSiv => SUITE_AES_256_GCM_SIV,
+    });
+    match data.kdf {
+        Kdf::Sha256 => out.push(KDF_SHA256),
+        Kdf::Argon2id(params) => {
+            out.push(KDF_ARGON2ID);
+            out.extend_from_slice(&params.m_cost.to_be_bytes());
+            out.extend_from_slice(&params.t_cost.to_be_bytes());
+            out.extend_from_slice(&params.p_cost.to_be_bytes());
+        }
+        Kdf::X25519 => out.push(KDF_X25519),
+        Kdf::Shamir { threshold, shares } => {
+            out.push(KDF_SHAMIR);
+            out.push(threshold);
+            out.push(shares);
+        }
+    }
+    // salt and nonce are generated by us and always fit in a u8 length.
+    out.push(data.salt.len() as u8);
+    out.extend_from_slice(&data.salt);
+    out.push(data.nonce.len() as u8);
+    out.extend_from_slice(&data.nonce);
+    if data.version >= ENVELOPE_V4 {
+        out.push(data.commitment.len() as u8);
+        out.extend_from_slice(&data.commitment);
+    }
+    if data.version >= ENVELOPE_VERSION {
+        out.push(match data.padding {
+            Padding::None => PADDING_NONE,
+            Padding::Buckets => PADDING_BUCKETS,
+            Padding::Padme => PADDING_PADME,
+        });
+    }
+    out
+}
+
+/// Parses an envelope produced by `to_bytes`.
+///
+/// # Errors
+/// Returns an error for unknown versions, suites, KDFs or padding
+/// schemes, for KDF costs above `MAX_KDF_PARAMS` and for truncated
+/// input.
+pub fn from_bytes(bytes: &[u8]) -> Result<EncryptData, CryptoError> {
+    let mut reader = Reader { bytes };
+
+    let version = reader.u8()?;
+    if !(ENVELOPE_V1..=ENVELOPE_VERSION).contains(&version) {
+        return Err(CryptoError::UnsupportedVersion(version));
+    }
+    let suite = match reader.u8()? {
+        SUITE_AES_256_GCM => CipherSuite::Aes256Gcm,
+        SUITE_CHACHA20_POLY1305 => CipherSuite::ChaCha20Poly1305,
+        SUITE_XCHACHA20_POLY1305 => CipherSuite::XChaCha20Poly1305,
+        SUITE_AES_256_GCM_SIV => CipherSuite::Aes256GcmSiv,
+        id => return Err(CryptoError::UnsupportedCipherSuite(id)),
+    };
+    let kdf = match reader.u8()? {
+        KDF_SHA256 => Kdf::Sha256,
+        KDF_ARGON2ID => Kdf::Argon2id(
+            KdfParams {
+                m_cost: reader.u32()?,
+                t_cost: reader.u32()?,
+                p_cost: reader.u32()?,
+            }
+            .check()?,
+        ),
+        KDF_X25519 => Kdf::X25519,
+        KDF_SHAMIR => Kdf::Shamir {
+            threshold: reader.u8()?,
+            shares: reader.u8()?,
+        },
+        id => return Err(CryptoError::UnsupportedKdf(id)),
+    };
+    let salt_len = reader.u8()? as usize;
+    let salt = reader.take(salt_len)?.to_vec();
+    let nonce_len = reader.u8()? as usize;
+    let nonce = reader.take(nonce_len)?.to_vec();
+
+    let mut commitment = Vec::new();
+    if version >= ENVELOPE_V4 {
+        let commitment_len = reader.u8()? as usize;
+        commitment = reader.take(commitment_len)?.to_vec();
+    }
+
+    let mut padding = Padding::None;
+    if version >= ENVELOPE_VERSION {
+        padding = match reader.u8()? {
+            PADDING_NONE => Padding::None,
+            PADDING_BUCKETS => Padding::Buckets,
+            PADDING_PADME => Padding::Padme,
+            id => return Err(CryptoError::UnsupportedPadding(id)),
+        };
+    }
+
+    let mut wrapped_key = None;
+    if version >= ENVELOPE_V3 {
+        let key_id_len = reader.u8()? as usize;
+        let key_id = String::from_utf8(reader.take(key_id_len)?.to_vec())
+            .map_err(|_| CryptoError::MalformedEnvelope)?;
+        let wrapped_len = reader.u8()? as usize;
+        let wrapped = reader.take(wrapped_len)?.to_vec();
+        if !key_id.is_empty() {
+            wrapped_key = Some(WrappedKey { key_id, wrapped });
+        }
+    }
+
+    Ok(EncryptData {
+        version,
+        suite,
+        kdf,
+        salt,
+        nonce,
+        commitment,
+        padding,
+        wrapped_key,
+        recipients: Vec::new(),
+        encrypted_text: reader.bytes.to_vec(),
+    })
+}
+
+/// Minimal cursor over the envelope bytes.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CryptoError> {
+        if self.bytes.len() < len {
+            return Err(CryptoError::MalformedEnvelope);
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, CryptoError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, CryptoError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_data(kdf: Kdf) -> EncryptData {
+        EncryptData {
+            version: ENVELOPE_VERSION,
+            suite: CipherSuite::Aes256Gcm,
+            kdf,
+            salt: vec![0x01, 0x02, 0x03],
+            nonce: vec![0x04, 0x05, 0x06],
+            commitment: vec![0x0c, 0x0d],
+            padding: Padding::Padme,
+            wrapped_key: Some(WrappedKey {
+                key_id: "k1".into(),
+                wrapped: vec![0x0a, 0x0b],
+            }),
+            recipients: Vec::new(),
+            encrypted_text: vec![0x07, 0x08, 0x09],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let kdfs = vec![
+            Kdf::Sha256,
+            Kdf::Argon2id(KdfParams {
+                m_cost: 19456,
+                t_cost: 2,
+                p_cost: 1,
+            }),
+            Kdf::X25519,
+            Kdf::Shamir {
+                threshold: 2,
+                shares: 3,
+            },
+        ];
+        for kdf in kdfs {
+            let data = test_data(kdf);
+            let got = from_bytes(&to_bytes(&data)).expect("failed to parse");
+            assert_eq!(data.version, got.version);
+            assert_eq!(data.suite, got.suite);
+            assert_eq!(data.kdf, got.kdf);
+            assert_eq!(data.salt, got.salt);
+            assert_eq!(data.nonce, got.nonce);
+            assert_eq!(data.commitment, got.commitment);
+            assert_eq!(data.padding, got.padding);
+            assert_eq!(data.wrapped_key, got.wrapped_key);
+            assert_eq!(data.encrypted_text, got.encrypted_text);
+        }
+    }
+
+    #[test]
+    fn test_rejects_excessive_kdf_cost() {
+        let data = test_data(Kdf::Argon2id(KdfParams {
+            m_cost: u32::MAX,
+            t_cost: 2,
+            p_cost: 1,
+        }));
+        assert!(matches!(
+            from_bytes(&to_bytes(&data)),
+            Err(CryptoError::KdfCostTooHigh)
+        ));
+    }
+
+    #[test]
+    fn test_rejects_unknown_version() {
+        let mut bytes = to_bytes(&test_data(Kdf::Sha256));
+        bytes[0] = 0xff;
+        assert!(matches!(
+            from_bytes(&bytes),
+            Err(CryptoError::UnsupportedVersion(0xff))
+        ));
+    }
+
+    #[test]
+    fn test_v2_has_no_master_key_section() {
+        let mut data = test_data(Kdf::Sha256);
+        data.version = ENVELOPE_V2;
+        let got = from_bytes(&to_bytes(&data)).expect("failed to parse");
+        assert_eq!(None, got.wrapped_key);
+        assert!(got.commitment.is_empty());
+        assert_eq!(Padding::None, got.padding);
+        assert_eq!(data.encrypted_text, got.encrypted_text);
+    }
+
+    #[test]
+    fn test_rejects_truncated() {
+        let bytes = to_bytes(&test_data(Kdf::Sha256));
+        assert!(matches!(
+            from_bytes(&bytes[..5]),
+            Err(CryptoError::MalformedEnvelope)
+        ));
+    }
+}
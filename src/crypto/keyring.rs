@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use aes_gcm::{
+    Aes256Gcm,
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use sha2::digest::generic_array::GenericArray;
+use zeroize::Zeroizing;
+
+use crate::crypto::{CryptoError, KEY_LEN, SecretKey, WrappedKey};
+
+/// Length in bytes of the nonce prepended to a wrapped data key.
+const WRAP_NONCE_LEN: usize = 12;
+
+/// Server-held key-encryption keys (KEKs), indexed by key id.
+/// Every configured key can unwrap; only the active key wraps.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    keys: HashMap<String, SecretKey>,
+    active: Option<String>,
+}
+
+impl Keyring {
+    /// Parses a keyring from a comma separated list of `id:base64key`
+    /// pairs and the id of the key new records are wrapped with.
+    ///
+    /// # Errors
+    /// Errors if a pair is malformed, an id is empty or longer than 255
+    /// bytes, a key is not 32 bytes, or the active id is not in the list.
+    pub fn from_spec(spec: &str, active: &str) -> Result<Self, String> {
+        let mut keys = HashMap::new();
+        for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (id, encoded) = pair
+                .split_once(':')
+                .ok_or_else(|| format!("master key '{}' is not in id:base64 form", pair))?;
+            if id.is_empty() || id.len() > u8::MAX as usize {
+                return Err(format!("master key id '{}' must be 1-255 bytes", id));
+            }
+            let decoded = Zeroizing::new(STANDARD.decode(encoded).unwrap_or_default());
+            if decoded.len() != KEY_LEN {
+                return Err(format!(
+                    "master key '{}' must be 32 base64 encoded bytes",
+                    id
+                ));
+            }
+            let mut key = SecretKey::default();
+            key.copy_from_slice(&decoded);
+            keys.insert(id.to_string(), key);
+        }
+        if !keys.contains_key(active) {
+            return Err(format!("active master key '{}' is not configured", active));
+        }
+        Ok(Keyring {
+            keys,
+            active: Some(active.to_string()),
+        })
+    }
+
+    /// The id of the key new records are wrapped with, if any.
+    pub fn active_id(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    /// Wraps a per-record data key with the active master key.
+    /// Returns `None` when no master key is configured.
+    ///
+    /// # Errors
+    /// Errors if encryption fails.
+    pub fn wrap(&self, data_key: &SecretKey, id: &str) -> Result<Option<WrappedKey>, CryptoError> {
+        let Some(key_id) = self.active_id() else {
+            return Ok(None);
+        };
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(self.keys[key_id].as_ref()));
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(
+            &nonce,
+            Payload {
+                msg: data_key.as_ref(),
+                aad: &wrap_associated_data(key_id, id),
+            },
+        )?;
+
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend_from_slice(&ciphertext);
+        Ok(Some(WrappedKey {
+            key_id: key_id.to_string(),
+            wrapped,
+        }))
+    }
+
+    /// Unwraps a data key with the master key it was wrapped under.
+    ///
+    /// # Errors
+    /// Errors if the key id is not configured, or with
+    /// `CryptoError::MasterKeyUnwrap` if the wrapped key does not
+    /// authenticate. It is never the caller's key that is wrong.
+    pub fn unwrap(&self, wrapped: &WrappedKey, id: &str) -> Result<SecretKey, CryptoError> {
+        let master_key = self
+            .keys
+            .get(&wrapped.key_id)
+            .ok_or_else(|| CryptoError::UnknownMasterKey(wrapped.key_id.clone()))?;
+        if wrapped.wrapped.len() < WRAP_NONCE_LEN {
+            return Err(CryptoError::MalformedEnvelope);
+        }
+        let (nonce, ciphertext) = wrapped.wrapped.split_at(WRAP_NONCE_LEN);
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(master_key.as_ref()));
+        let decrypted = Zeroizing::new(
+            cipher
+                .decrypt(
+                    GenericArray::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: &wrap_associated_data(&wrapped.key_id, id),
+                    },
+                )
+                .map_err(|_| CryptoError::MasterKeyUnwrap)?,
+        );
+        if decrypted.len() != KEY_LEN {
+            return Err(CryptoError::MalformedEnvelope);
+        }
+        let mut data_key = SecretKey::default();
+        data_key.copy_from_slice(&decrypted);
+        Ok(data_key)
+    }
+}
+
+/// Binds a wrapped data key to its record and master key id.
+fn wrap_associated_data(key_id: &str, id: &str) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(1 + key_id.len() + id.len());
+    // key ids are limited to 255 bytes by the envelope.
+    aad.push(key_id.len() as u8);
+    aad.extend_from_slice(key_id.as_bytes());
+    aad.extend_from_slice(id.as_bytes());
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    const KEY_B: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+
+    #[test]
+    fn test_from_spec() {
+        let keyring = Keyring::from_spec(&format!("a:{},b:{}", KEY_A, KEY_B), "b").unwrap();
+        assert_eq!(Some("b"), keyring.active_id());
+        assert!(Keyring::from_spec(&format!("a:{}", KEY_A), "b").is_err());
+        assert!(Keyring::from_spec("a:c2hvcnQ=", "a").is_err());
+        assert!(Keyring::from_spec("a", "a").is_err());
+    }
+
+    #[test]
+    fn test_wrap_unwrap() {
+        let keyring = Keyring::from_spec(&format!("a:{},b:{}", KEY_A, KEY_B), "a").unwrap();
+        let data_key = SecretKey::new([7u8; KEY_LEN]);
+        let wrapped = keyring.wrap(&data_key, "id").unwrap().unwrap();
+        assert_eq!("a", wrapped.key_id);
+        assert_eq!(*data_key, *keyring.unwrap(&wrapped, "id").unwrap());
+        assert_eq!(
+            Err(CryptoError::MasterKeyUnwrap),
+            keyring.unwrap(&wrapped, "other").map(|_| ())
+        );
+
+        let retired = Keyring::from_spec(&format!("b:{}", KEY_B), "b").unwrap();
+        assert!(matches!(
+            retired.unwrap(&wrapped, "id"),
+            Err(CryptoError::UnknownMasterKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_empty_keyring_does_not_wrap() {
+        assert!(
+            Keyring::default()
+                .wrap(&SecretKey::default(), "id")
+                .unwrap()
+                .is_none()
+        );
+    }
+}
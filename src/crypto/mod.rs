@@ -1,59 +1,601 @@
-use aes_gcm::{
-    Aes256Gcm,
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use subtle::ConstantTimeEq;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, Zeroizing};
+
+pub mod envelope;
+pub mod keyring;
+mod padding;
+pub mod recipient;
+pub mod shamir;
+pub mod stream;
+mod suite;
+pub mod token;
+
+pub use padding::Padding;
+pub use suite::CipherSuite;
+
+use envelope::{
+    ENVELOPE_V1, ENVELOPE_V2, ENVELOPE_V3, ENVELOPE_V4, ENVELOPE_VERSION, LEGACY_VERSION,
+};
+use keyring::Keyring;
+use recipient::RecipientStanza;
+use shamir::Share;
+
+/// Length in bytes of the random salt generated for every record.
+pub const SALT_LEN: usize = 16;
+
+/// Length in bytes of the derived key. Every cipher suite uses 256-bit keys.
+const KEY_LEN: usize = 32;
+
+/// A 256-bit key that is wiped from memory on drop.
+pub type SecretKey = Zeroizing<[u8; KEY_LEN]>;
+
+/// HKDF info used to mix the user key with the unwrapped data key.
+const CONTENT_KEY_INFO: &[u8] = b"cipherlink content key v3";
+
+/// HKDF info for the AEAD key of version 4 records.
+const ENCRYPTION_KEY_INFO: &[u8] = b"cipherlink encryption key v4";
+
+/// HKDF info for the key commitment of version 4 records.
+const COMMITMENT_INFO: &[u8] = b"cipherlink key commitment v4";
+
+/// Argon2id cost parameters. Stored alongside every record so the
+/// parameters can be tuned without breaking older records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory cost in KiB.
+    pub m_cost: u32,
+    /// Number of iterations.
+    pub t_cost: u32,
+    /// Degree of parallelism.
+    pub p_cost: u32,
+}
+
+/// The highest Argon2id costs a record may carry. Costs are read back
+/// from stored records, so without a ceiling a tampered record could
+/// make every decrypt allocate memory or burn CPU without limit.
+pub const MAX_KDF_PARAMS: KdfParams = KdfParams {
+    m_cost: 256 * 1024,
+    t_cost: 10,
+    p_cost: 8,
 };
-use sha2::{Digest, Sha256, digest::generic_array::GenericArray};
 
+impl KdfParams {
+    /// Checks the costs don't exceed `MAX_KDF_PARAMS`.
+    ///
+    /// # Errors
+    /// Errors with `CryptoError::KdfCostTooHigh` if any of them does.
+    pub fn check(self) -> Result<Self, CryptoError> {
+        if self.m_cost > MAX_KDF_PARAMS.m_cost
+            || self.t_cost > MAX_KDF_PARAMS.t_cost
+            || self.p_cost > MAX_KDF_PARAMS.p_cost
+        {
+            return Err(CryptoError::KdfCostTooHigh);
+        }
+        Ok(self)
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// The function used to turn the user supplied key into an AES key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kdf {
+    /// Single unsalted SHA-256. Only kept so records written before
+    /// Argon2id was introduced can still be decrypted.
+    Sha256,
+    /// Salted Argon2id with the parameters used at encryption time.
+    Argon2id(KdfParams),
+    /// No user key: a random file key is wrapped to X25519 recipients
+    /// in the record's stanzas.
+    X25519,
+    /// No user key: a random file key is split into `shares` Shamir
+    /// shares, any `threshold` of which recover it.
+    Shamir { threshold: u8, shares: u8 },
+}
+
+/// Everything `encrypt` needs besides the user input.
+#[derive(Clone, Default)]
+pub struct CryptoConfig {
+    pub kdf_params: KdfParams,
+    pub keyring: Keyring,
+    /// Suite new records are sealed with.
+    pub suite: CipherSuite,
+    /// Padding applied to new records before sealing.
+    pub padding: Padding,
+}
+
+/// A per-record data key wrapped under a server-held master key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WrappedKey {
+    /// Id of the master key in the keyring.
+    pub key_id: String,
+    /// Nonce followed by the AES-GCM sealed data key.
+    pub wrapped: Vec<u8>,
+}
+
+/// Errors produced while sealing, opening or parsing a record.
+#[derive(Debug, PartialEq)]
+pub enum CryptoError {
+    /// The envelope version is not one this build understands.
+    UnsupportedVersion(u8),
+    /// The envelope names a cipher suite this build does not implement.
+    UnsupportedCipherSuite(u8),
+    /// The envelope names a KDF this build does not implement.
+    UnsupportedKdf(u8),
+    /// The envelope names a padding scheme this build does not implement.
+    UnsupportedPadding(u8),
+    /// The envelope is truncated or otherwise unparseable.
+    MalformedEnvelope,
+    /// The KDF parameters were rejected or hashing failed.
+    KeyDerivation,
+    /// The KDF costs exceed `MAX_KDF_PARAMS`.
+    KdfCostTooHigh,
+    /// AEAD sealing or opening failed, e.g. wrong key.
+    Aead,
+    /// The record was wrapped under a master key that is not configured.
+    UnknownMasterKey(String),
+    /// The wrapped data key doesn't open under its master key, e.g. it
+    /// was tampered with. The user key plays no part in it.
+    MasterKeyUnwrap,
+    /// The key does not match the record's key commitment.
+    KeyCommitment,
+    /// None of the record's recipient stanzas open with the identity.
+    NoMatchingRecipient,
+    /// Secret sharing parameters or shares are invalid.
+    InvalidShares,
+    /// The record is unlocked another way, e.g. a passphrase sent for a
+    /// record encrypted to recipients.
+    WrongUnlockMethod,
+}
+
+impl CryptoError {
+    /// Whether the caller's key, identity or shares were wrong, as
+    /// opposed to the record or the server's config.
+    pub fn is_wrong_key(&self) -> bool {
+        matches!(
+            self,
+            CryptoError::Aead
+                | CryptoError::KeyCommitment
+                | CryptoError::NoMatchingRecipient
+                | CryptoError::InvalidShares
+        )
+    }
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::UnsupportedVersion(v) => write!(f, "unsupported envelope version {}", v),
+            CryptoError::UnsupportedCipherSuite(id) => write!(f, "unsupported cipher suite {}", id),
+            CryptoError::UnsupportedKdf(id) => write!(f, "unsupported kdf {}", id),
+            CryptoError::UnsupportedPadding(id) => write!(f, "unsupported padding {}", id),
+            CryptoError::MalformedEnvelope => write!(f, "malformed envelope"),
+            CryptoError::KeyDerivation => write!(f, "key derivation failed"),
+            CryptoError::KdfCostTooHigh => write!(f, "kdf cost above the allowed maximum"),
+            CryptoError::Aead => write!(f, "aead operation failed"),
+            CryptoError::UnknownMasterKey(id) => write!(f, "unknown master key '{}'", id),
+            CryptoError::MasterKeyUnwrap => write!(f, "wrapped data key doesn't open"),
+            CryptoError::KeyCommitment => write!(f, "key commitment mismatch"),
+            CryptoError::NoMatchingRecipient => write!(f, "no matching recipient"),
+            CryptoError::InvalidShares => write!(f, "invalid secret shares"),
+            CryptoError::WrongUnlockMethod => write!(f, "wrong unlock method for this record"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+impl From<aes_gcm::Error> for CryptoError {
+    fn from(_: aes_gcm::Error) -> Self {
+        CryptoError::Aead
+    }
+}
+
+/// A sealed record. Serialized with `envelope::to_bytes` for storage.
+#[derive(Clone)]
 pub struct EncryptData {
+    pub version: u8,
+    pub suite: CipherSuite,
+    pub kdf: Kdf,
+    pub salt: Vec<u8>,
     pub nonce: Vec<u8>,
+    /// Commits the record to a single key. Empty before version 4.
+    pub commitment: Vec<u8>,
+    /// Padding applied before sealing. `None` before version 5.
+    pub padding: Padding,
+    /// Only set on version 3 records when a master key was configured.
+    pub wrapped_key: Option<WrappedKey>,
+    /// File key wrapped per recipient. Stored beside the envelope and
+    /// only set when `kdf` is `Kdf::X25519`.
+    pub recipients: Vec<RecipientStanza>,
     pub encrypted_text: Vec<u8>,
 }
 
-/// Encrypts the user provided plain_text with the given key.
-/// Returns the encrypted text and nonce used during encryption as a struct.
+/// Encrypts the user provided plain_text with the given key, using the
+/// configured cipher suite.
+/// The key is derived with Argon2id using a fresh random salt.
+/// If the keyring has an active master key, a random data key is
+/// mixed in and stored wrapped under the master key, so the record
+/// can't be brute-forced from the table alone.
+/// The record id and envelope header are authenticated as associated
+/// data, so the ciphertext only decrypts under the same id.
+/// AES-GCM and friends are not key-committing, so a commitment to the
+/// key is stored as well and the AEAD runs under a key derived from it.
+/// The plain text is padded with the configured scheme first, so the
+/// stored ciphertext doesn't reveal its exact length.
+/// Returns the encrypted text, salt, KDF and nonce used during
+/// encryption as a struct.
 ///
 /// # Errors
-/// Returns an error if encryption fails.
+/// Returns an error if key derivation or encryption fails.
 ///
 /// # Safety
 /// This function does not panic under normal conditions.
-pub fn encrypt(plain_text: &str, key: &str) -> Result<EncryptData, aes_gcm::Error> {
-    // Make a 32-byte key from the user supplied key, otherwise AES256GCM panics.
-    let derived_key = Sha256::digest(key.as_bytes());
-    let cipher = Aes256Gcm::new(&derived_key);
-    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    let ciphertext = cipher.encrypt(&nonce, plain_text.as_bytes())?;
+pub fn encrypt(
+    plain_text: &str,
+    key: &str,
+    id: &str,
+    config: &CryptoConfig,
+) -> Result<EncryptData, CryptoError> {
+    let mut salt = vec![0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
 
-    Ok(EncryptData {
-        nonce: nonce.to_vec(),
-        encrypted_text: ciphertext,
-    })
+    let kdf = Kdf::Argon2id(config.kdf_params);
+    let derived_key = derive_key(&kdf, &salt, key)?;
+    seal(plain_text, kdf, salt, &derived_key, Vec::new(), id, config)
+}
+
+/// Encrypts the plain_text to one or more X25519 public keys instead of
+/// a shared key. A random file key takes the place of the derived key
+/// and is wrapped to every recipient, so any of their private keys can
+/// decrypt. Master key wrapping, commitment and padding work as in
+/// `encrypt`.
+///
+/// # Errors
+/// Returns an error if there are no recipients, a public key is a low
+/// order point, or encryption fails.
+pub fn encrypt_to_recipients(
+    plain_text: &str,
+    recipients: &[PublicKey],
+    id: &str,
+    config: &CryptoConfig,
+) -> Result<EncryptData, CryptoError> {
+    if recipients.is_empty() {
+        return Err(CryptoError::NoMatchingRecipient);
+    }
+    let mut file_key = SecretKey::default();
+    OsRng.fill_bytes(file_key.as_mut());
+    let stanzas = recipients
+        .iter()
+        .map(|recipient| recipient::wrap(&file_key, recipient, id))
+        .collect::<Result<Vec<_>, _>>()?;
+    seal(
+        plain_text,
+        Kdf::X25519,
+        Vec::new(),
+        &file_key,
+        stanzas,
+        id,
+        config,
+    )
+}
+
+/// Encrypts the plain_text under a random file key split into `shares`
+/// Shamir shares, so that any `threshold` of them decrypt. The shares
+/// are returned to be handed out and are not stored. Master key
+/// wrapping, commitment and padding work as in `encrypt`.
+///
+/// # Errors
+/// Returns an error unless 1 <= threshold <= shares, or if encryption
+/// fails.
+pub fn encrypt_split(
+    plain_text: &str,
+    threshold: u8,
+    shares: u8,
+    id: &str,
+    config: &CryptoConfig,
+) -> Result<(EncryptData, Vec<Share>), CryptoError> {
+    let mut file_key = SecretKey::default();
+    OsRng.fill_bytes(file_key.as_mut());
+    let split = shamir::split(file_key.as_ref(), threshold, shares)?;
+    let data = seal(
+        plain_text,
+        Kdf::Shamir { threshold, shares },
+        Vec::new(),
+        &file_key,
+        Vec::new(),
+        id,
+        config,
+    )?;
+    Ok((data, split))
+}
+
+/// Seals a record under the key derived from the user secret.
+fn seal(
+    plain_text: &str,
+    kdf: Kdf,
+    salt: Vec<u8>,
+    user_key: &SecretKey,
+    recipients: Vec<RecipientStanza>,
+    id: &str,
+    config: &CryptoConfig,
+) -> Result<EncryptData, CryptoError> {
+    let mut derived_key = user_key.clone();
+    let mut data_key = SecretKey::default();
+    OsRng.fill_bytes(data_key.as_mut());
+    let wrapped_key = config.keyring.wrap(&data_key, id)?;
+    if wrapped_key.is_some() {
+        derived_key = content_key(&derived_key, &data_key);
+    }
+
+    let (encryption_key, commitment) = commit_to_key(&derived_key);
+
+    let suite = config.suite;
+    let mut data = EncryptData {
+        version: ENVELOPE_VERSION,
+        suite,
+        kdf,
+        salt,
+        nonce: suite.generate_nonce(),
+        commitment,
+        padding: config.padding,
+        wrapped_key,
+        recipients,
+        encrypted_text: Vec::new(),
+    };
+    let aad = associated_data(&data, id);
+    let padded = Zeroizing::new(data.padding.pad(plain_text.as_bytes()));
+    data.encrypted_text = suite.seal(encryption_key.as_ref(), &data.nonce, &padded, &aad)?;
+
+    Ok(data)
 }
 
 /// Decrypts the user provided encrypted text with the user provided key
-/// and nonce. Returns the plain text of the provided encrypted text.
+/// and nonce. The key is derived again with the KDF recorded in `data`,
+/// and the data key is unwrapped from the keyring if the record has one.
+/// `id` must be the id the record was encrypted under.
+/// Returns the plain text of the provided encrypted text with the
+/// padding removed, wiped from memory once dropped.
 ///
 /// # Errors
-/// Returns an error if decryption fails.
+/// Returns an error if the envelope version is unknown, the master key
+/// is not in the keyring, the key doesn't match the key commitment, or
+/// if key derivation or decryption fails.
 ///
 /// # Safety
 /// This function does not panic under normal conditions.
-pub fn decrypt(data: &EncryptData, key: &str) -> Result<Vec<u8>, aes_gcm::Error> {
-    // derive the key again.
-    let derived_key = Sha256::digest(key.as_bytes());
-    let cipher = Aes256Gcm::new(&derived_key.into());
-
-    let plaintext = cipher.decrypt(
-        GenericArray::from_slice(&data.nonce),
-        data.encrypted_text.as_ref(),
-    )?;
+pub fn decrypt(
+    data: &EncryptData,
+    key: &str,
+    id: &str,
+    keyring: &Keyring,
+) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    let aad = record_associated_data(data, id)?;
+    let derived_key = derive_key(&data.kdf, &data.salt, key)?;
+    open(data, derived_key, &aad, id, keyring)
+}
+
+/// Decrypts a record encrypted with `encrypt_to_recipients` using one
+/// recipient's X25519 private key.
+///
+/// # Errors
+/// Returns an error if the record was not encrypted to recipients or
+/// none of its stanzas is addressed to the identity, and otherwise in
+/// the same cases as `decrypt`.
+pub fn decrypt_with_identity(
+    data: &EncryptData,
+    identity: &StaticSecret,
+    id: &str,
+    keyring: &Keyring,
+) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    let aad = record_associated_data(data, id)?;
+    if data.kdf != Kdf::X25519 {
+        return Err(CryptoError::WrongUnlockMethod);
+    }
+    let file_key = recipient::unwrap(&data.recipients, identity, id)?;
+    open(data, file_key, &aad, id, keyring)
+}
+
+/// Decrypts a record encrypted with `encrypt_split` by combining at
+/// least `threshold` of its shares.
+///
+/// # Errors
+/// Returns an error if the record was not split, there are too few or
+/// invalid shares, the combined key doesn't match the key commitment,
+/// and otherwise in the same cases as `decrypt`.
+pub fn decrypt_with_shares(
+    data: &EncryptData,
+    shares: &[Share],
+    id: &str,
+    keyring: &Keyring,
+) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    let aad = record_associated_data(data, id)?;
+    let Kdf::Shamir { threshold, .. } = data.kdf else {
+        return Err(CryptoError::WrongUnlockMethod);
+    };
+    if shares.len() < threshold as usize {
+        return Err(CryptoError::InvalidShares);
+    }
+    let combined = shamir::combine(shares)?;
+    if combined.len() != KEY_LEN {
+        return Err(CryptoError::InvalidShares);
+    }
+    let mut file_key = SecretKey::default();
+    file_key.copy_from_slice(&combined);
+    open(data, file_key, &aad, id, keyring)
+}
+
+/// The associated data a record was sealed with, by envelope version.
+fn record_associated_data(data: &EncryptData, id: &str) -> Result<Vec<u8>, CryptoError> {
+    // Legacy records and version 1 envelopes were sealed without
+    // associated data.
+    match data.version {
+        LEGACY_VERSION | ENVELOPE_V1 => Ok(Vec::new()),
+        ENVELOPE_V2 | ENVELOPE_V3 | ENVELOPE_V4 | ENVELOPE_VERSION => Ok(associated_data(data, id)),
+        version => Err(CryptoError::UnsupportedVersion(version)),
+    }
+}
+
+/// Opens a record with the key derived from the user secret.
+fn open(
+    data: &EncryptData,
+    mut derived_key: SecretKey,
+    aad: &[u8],
+    id: &str,
+    keyring: &Keyring,
+) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    if let Some(wrapped_key) = &data.wrapped_key {
+        let data_key = keyring.unwrap(wrapped_key, id)?;
+        derived_key = content_key(&derived_key, &data_key);
+    }
+
+    // Check the commitment before the AEAD, so a ciphertext crafted to
+    // open under several keys never releases plaintext for the others.
+    if data.version >= ENVELOPE_V4 {
+        let (encryption_key, commitment) = commit_to_key(&derived_key);
+        if !bool::from(commitment.ct_eq(&data.commitment)) {
+            return Err(CryptoError::KeyCommitment);
+        }
+        derived_key = encryption_key;
+    }
+
+    let mut plain_text = Zeroizing::new(data.suite.open(
+        derived_key.as_ref(),
+        &data.nonce,
+        &data.encrypted_text,
+        aad,
+    )?);
+    data.padding.unpad(&mut plain_text)?;
+    Ok(plain_text)
+}
+
+/// Re-wraps the record's data key under the keyring's active master key
+/// without touching the ciphertext, so the user key is not needed.
+/// Returns false if the record has no wrapped key or is already wrapped
+/// under the active key.
+///
+/// # Errors
+/// Returns an error if the current master key is missing from the
+/// keyring or the wrapped key doesn't authenticate.
+pub fn rewrap(data: &mut EncryptData, id: &str, keyring: &Keyring) -> Result<bool, CryptoError> {
+    let Some(wrapped_key) = &data.wrapped_key else {
+        return Ok(false);
+    };
+    if Some(wrapped_key.key_id.as_str()) == keyring.active_id() {
+        return Ok(false);
+    }
+    let data_key = keyring.unwrap(wrapped_key, id)?;
+    match keyring.wrap(&data_key, id)? {
+        Some(rewrapped) => {
+            data.wrapped_key = Some(rewrapped);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Splits a key into the AEAD key and a commitment to it. Both come
+/// from HKDF-SHA256, so finding two keys with the same commitment means
+/// finding a SHA-256 collision.
+fn commit_to_key(key: &SecretKey) -> (SecretKey, Vec<u8>) {
+    let hkdf = Hkdf::<Sha256>::new(None, key.as_ref());
+    let mut encryption_key = SecretKey::default();
+    let mut commitment = vec![0u8; KEY_LEN];
+    hkdf.expand(ENCRYPTION_KEY_INFO, encryption_key.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    hkdf.expand(COMMITMENT_INFO, &mut commitment)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    (encryption_key, commitment)
+}
 
-    Ok(plaintext)
+/// Mixes the key derived from the user key with the unwrapped data key.
+fn content_key(derived_key: &SecretKey, data_key: &SecretKey) -> SecretKey {
+    let mut ikm = Zeroizing::new([0u8; KEY_LEN * 2]);
+    ikm[..KEY_LEN].copy_from_slice(derived_key.as_ref());
+    ikm[KEY_LEN..].copy_from_slice(data_key.as_ref());
+    let mut okm = SecretKey::default();
+    Hkdf::<Sha256>::new(None, ikm.as_ref())
+        .expand(CONTENT_KEY_INFO, okm.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    okm
+}
+
+/// The envelope header followed by the record id. The header is
+/// self-delimiting so the concatenation is unambiguous.
+fn associated_data(data: &EncryptData, id: &str) -> Vec<u8> {
+    let mut aad = envelope::header_bytes(data);
+    aad.extend_from_slice(id.as_bytes());
+    aad
+}
+
+/// Make a 32-byte key from the user supplied key, otherwise AES256GCM panics.
+fn derive_key(kdf: &Kdf, salt: &[u8], key: &str) -> Result<SecretKey, CryptoError> {
+    match kdf {
+        Kdf::Sha256 => {
+            let mut digest: [u8; KEY_LEN] = Sha256::digest(key.as_bytes()).into();
+            let derived_key = SecretKey::new(digest);
+            digest.zeroize();
+            Ok(derived_key)
+        }
+        Kdf::Argon2id(params) => {
+            let params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(KEY_LEN))
+                .map_err(|_| CryptoError::KeyDerivation)?;
+            let mut derived_key = SecretKey::default();
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password_into(key.as_bytes(), salt, derived_key.as_mut())
+                .map_err(|_| CryptoError::KeyDerivation)?;
+            Ok(derived_key)
+        }
+        // There is no user key to derive from, the file key is in the
+        // recipient stanzas or shares.
+        Kdf::X25519 | Kdf::Shamir { .. } => Err(CryptoError::WrongUnlockMethod),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use aes::{
+        Aes256,
+        cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray},
+    };
+
+    /// Cheap parameters so the tests don't spend seconds hashing.
+    const TEST_PARAMS: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    const MASTER_KEY_A: &str = "a:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    const MASTER_KEY_B: &str = "b:AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+
+    fn test_config() -> CryptoConfig {
+        CryptoConfig {
+            kdf_params: TEST_PARAMS,
+            keyring: Keyring::default(),
+            suite: CipherSuite::Aes256Gcm,
+            padding: Padding::default(),
+        }
+    }
+
+    fn keyring(spec: &str, active: &str) -> Keyring {
+        Keyring::from_spec(spec, active).expect("invalid keyring")
+    }
 
     #[test]
     fn test_encrypt_decrypt() {
@@ -66,13 +608,338 @@ mod tests {
             ("abc", "bar"),
         ];
         for (plaintext, key) in tests {
-            let got_encryption = encrypt(plaintext, key).expect("encryption failed");
-            let got_decryption = decrypt(&got_encryption, key).expect("decryption failed");
+            let got_encryption =
+                encrypt(plaintext, key, "id", &test_config()).expect("encryption failed");
+            let got_decryption = decrypt(&got_encryption, key, "id", &Keyring::default())
+                .expect("decryption failed");
 
-            assert_eq!(plaintext, String::from_utf8(got_decryption).unwrap());
+            assert_eq!(
+                plaintext,
+                String::from_utf8(got_decryption.to_vec()).unwrap()
+            );
 
             println!("ciph: {:?}", got_encryption.encrypted_text);
             println!("nonce: {:?}", got_encryption.nonce);
         }
     }
+
+    #[test]
+    fn test_salt_is_random_per_record() {
+        let first = encrypt("abc", "key", "id", &test_config()).expect("encryption failed");
+        let second = encrypt("abc", "key", "id", &test_config()).expect("encryption failed");
+        assert_eq!(SALT_LEN, first.salt.len());
+        assert_ne!(first.salt, second.salt);
+        assert!(decrypt(&first, "wrong", "id", &Keyring::default()).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_legacy_sha256() {
+        // Records written before Argon2id: unsalted SHA-256 of the key.
+        let key = "music";
+        let derived_key = Sha256::digest(key.as_bytes());
+        let nonce = CipherSuite::Aes256Gcm.generate_nonce();
+        let encrypted_text = CipherSuite::Aes256Gcm
+            .seal(&derived_key, &nonce, "http://yahoo.com".as_bytes(), &[])
+            .unwrap();
+        let data = EncryptData {
+            version: LEGACY_VERSION,
+            suite: CipherSuite::Aes256Gcm,
+            kdf: Kdf::Sha256,
+            salt: Vec::new(),
+            nonce,
+            commitment: Vec::new(),
+            padding: Padding::None,
+            wrapped_key: None,
+            recipients: Vec::new(),
+            encrypted_text,
+        };
+
+        let got = decrypt(&data, key, "id1", &Keyring::default()).expect("decryption failed");
+        assert_eq!("http://yahoo.com", String::from_utf8(got.to_vec()).unwrap());
+    }
+
+    #[test]
+    fn test_decrypt_rejects_unknown_version() {
+        let mut data = encrypt("abc", "key", "id", &test_config()).expect("encryption failed");
+        data.version = 0xff;
+        assert!(matches!(
+            decrypt(&data, "key", "id", &Keyring::default()),
+            Err(CryptoError::UnsupportedVersion(0xff))
+        ));
+    }
+
+    #[test]
+    fn test_ciphertext_bound_to_id() {
+        let data = encrypt("abc", "key", "id1", &test_config()).expect("encryption failed");
+        assert!(decrypt(&data, "key", "id1", &Keyring::default()).is_ok());
+        // Same nonce and cipher text copied under another id.
+        assert!(matches!(
+            decrypt(&data, "key", "id2", &Keyring::default()),
+            Err(CryptoError::Aead)
+        ));
+    }
+
+    #[test]
+    fn test_header_is_authenticated() {
+        let mut data = encrypt("abc", "key", "id", &test_config()).expect("encryption failed");
+        // Downgrading to v1 would drop the associated data.
+        data.version = ENVELOPE_V1;
+        assert!(decrypt(&data, "key", "id", &Keyring::default()).is_err());
+    }
+
+    #[test]
+    fn test_master_key_required() {
+        let config = CryptoConfig {
+            keyring: keyring(MASTER_KEY_A, "a"),
+            ..test_config()
+        };
+        let data = encrypt("abc", "key", "id", &config).expect("encryption failed");
+        assert_eq!("a", data.wrapped_key.as_ref().unwrap().key_id);
+
+        let got = decrypt(&data, "key", "id", &config.keyring).expect("decryption failed");
+        assert_eq!("abc", String::from_utf8(got.to_vec()).unwrap());
+        // The user key alone is not enough.
+        assert!(matches!(
+            decrypt(&data, "key", "id", &Keyring::default()),
+            Err(CryptoError::UnknownMasterKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_rewrap() {
+        let config = CryptoConfig {
+            keyring: keyring(MASTER_KEY_A, "a"),
+            ..test_config()
+        };
+        let mut data = encrypt("abc", "key", "id", &config).expect("encryption failed");
+
+        let both = keyring(&format!("{},{}", MASTER_KEY_A, MASTER_KEY_B), "b");
+        assert!(rewrap(&mut data, "id", &both).expect("rewrap failed"));
+        assert_eq!("b", data.wrapped_key.as_ref().unwrap().key_id);
+        assert!(!rewrap(&mut data, "id", &both).expect("rewrap failed"));
+
+        // The old master key can be retired after rotation.
+        let rotated = keyring(MASTER_KEY_B, "b");
+        let got = decrypt(&data, "key", "id", &rotated).expect("decryption failed");
+        assert_eq!("abc", String::from_utf8(got.to_vec()).unwrap());
+    }
+
+    #[test]
+    fn test_cipher_suites() {
+        let suites = [
+            CipherSuite::Aes256Gcm,
+            CipherSuite::ChaCha20Poly1305,
+            CipherSuite::XChaCha20Poly1305,
+            CipherSuite::Aes256GcmSiv,
+        ];
+        for suite in suites {
+            let config = CryptoConfig {
+                suite,
+                ..test_config()
+            };
+            let data = encrypt("abc", "key", "id", &config).expect("encryption failed");
+            assert_eq!(suite, data.suite);
+            assert_eq!(suite.nonce_len(), data.nonce.len());
+
+            // The suite is read back from the envelope, not from config.
+            let parsed = envelope::from_bytes(&envelope::to_bytes(&data)).expect("parse failed");
+            let got =
+                decrypt(&parsed, "key", "id", &Keyring::default()).expect("decryption failed");
+            assert_eq!("abc", String::from_utf8(got.to_vec()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_padding_hides_length() {
+        let short =
+            encrypt("https://a.io", "key", "id", &test_config()).expect("encryption failed");
+        let long = encrypt(
+            "https://example.com/some/longer/path",
+            "key",
+            "id",
+            &test_config(),
+        )
+        .expect("encryption failed");
+        assert_eq!(Padding::Buckets, short.padding);
+        assert_eq!(short.encrypted_text.len(), long.encrypted_text.len());
+
+        let got = decrypt(&short, "key", "id", &Keyring::default()).expect("decryption failed");
+        assert_eq!("https://a.io", String::from_utf8(got.to_vec()).unwrap());
+
+        // The padding scheme is authenticated with the header.
+        let mut tampered = short;
+        tampered.padding = Padding::None;
+        assert!(decrypt(&tampered, "key", "id", &Keyring::default()).is_err());
+    }
+
+    #[test]
+    fn test_recipients() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let bob = StaticSecret::random_from_rng(OsRng);
+        let recipients = [PublicKey::from(&alice), PublicKey::from(&bob)];
+        let data = encrypt_to_recipients("abc", &recipients, "id", &test_config())
+            .expect("encryption failed");
+        assert_eq!(Kdf::X25519, data.kdf);
+        assert_eq!(2, data.recipients.len());
+
+        for identity in [&alice, &bob] {
+            let got = decrypt_with_identity(&data, identity, "id", &Keyring::default())
+                .expect("decryption failed");
+            assert_eq!("abc", String::from_utf8(got.to_vec()).unwrap());
+        }
+        let eve = StaticSecret::random_from_rng(OsRng);
+        assert!(matches!(
+            decrypt_with_identity(&data, &eve, "id", &Keyring::default()),
+            Err(CryptoError::NoMatchingRecipient)
+        ));
+        // There is no passphrase or shares to try.
+        assert!(matches!(
+            decrypt(&data, "", "id", &Keyring::default()),
+            Err(CryptoError::WrongUnlockMethod)
+        ));
+        assert!(matches!(
+            decrypt_with_shares(&data, &[], "id", &Keyring::default()),
+            Err(CryptoError::WrongUnlockMethod)
+        ));
+    }
+
+    #[test]
+    fn test_split() {
+        let (data, shares) =
+            encrypt_split("abc", 2, 3, "id", &test_config()).expect("split failed");
+        assert_eq!(
+            Kdf::Shamir {
+                threshold: 2,
+                shares: 3
+            },
+            data.kdf
+        );
+
+        let got = decrypt_with_shares(&data, &shares[1..], "id", &Keyring::default())
+            .expect("decryption failed");
+        assert_eq!("abc", String::from_utf8(got.to_vec()).unwrap());
+        assert!(matches!(
+            decrypt_with_shares(&data, &shares[..1], "id", &Keyring::default()),
+            Err(CryptoError::InvalidShares)
+        ));
+
+        // Shares of another record combine to the wrong key.
+        let (_, other) = encrypt_split("abc", 2, 3, "id", &test_config()).expect("split failed");
+        assert!(matches!(
+            decrypt_with_shares(&data, &other[..2], "id", &Keyring::default()),
+            Err(CryptoError::KeyCommitment)
+        ));
+    }
+
+    /// GF(2^128) multiplication in GCM's bit order (NIST SP 800-38D).
+    fn gf_mul(x: u128, y: u128) -> u128 {
+        let mut z = 0;
+        let mut v = y;
+        for i in 0..128 {
+            if (x >> (127 - i)) & 1 == 1 {
+                z ^= v;
+            }
+            v = if v & 1 == 1 {
+                (v >> 1) ^ (0xe1 << 120)
+            } else {
+                v >> 1
+            };
+        }
+        z
+    }
+
+    /// x^(2^128 - 2), the multiplicative inverse of x.
+    fn gf_inv(x: u128) -> u128 {
+        // The identity element in GCM's bit order.
+        let mut result = 1 << 127;
+        for bit in (0..128).rev() {
+            result = gf_mul(result, result);
+            if bit != 0 {
+                result = gf_mul(result, x);
+            }
+        }
+        result
+    }
+
+    fn aes_block(key: &[u8], block: u128) -> u128 {
+        let cipher = Aes256::new_from_slice(key).unwrap();
+        let mut block = GenericArray::from(block.to_be_bytes());
+        cipher.encrypt_block(&mut block);
+        u128::from_be_bytes(block.into())
+    }
+
+    fn ghash(h: u128, aad: &[u8], ciphertext: &[u8]) -> u128 {
+        let mut x = 0;
+        for data in [aad, ciphertext] {
+            for chunk in data.chunks(16) {
+                let mut block = [0u8; 16];
+                block[..chunk.len()].copy_from_slice(chunk);
+                x = gf_mul(x ^ u128::from_be_bytes(block), h);
+            }
+        }
+        let lens = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+        gf_mul(x ^ lens, h)
+    }
+
+    /// AES-256-GCM tag for a 96-bit nonce.
+    fn gcm_tag(key: &[u8], nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> u128 {
+        let mut j0 = [0u8; 16];
+        j0[..12].copy_from_slice(nonce);
+        j0[15] = 1;
+        aes_block(key, u128::from_be_bytes(j0)) ^ ghash(aes_block(key, 0), aad, ciphertext)
+    }
+
+    #[test]
+    fn test_multi_key_ciphertext_rejected() {
+        let id = "id";
+        let aead_key = |key: &str| commit_to_key(&derive_key(&Kdf::Sha256, &[], key).unwrap());
+        let (key1, commitment1) = aead_key("key1");
+        let (key2, _) = aead_key("key2");
+
+        let mut data = EncryptData {
+            version: ENVELOPE_VERSION,
+            suite: CipherSuite::Aes256Gcm,
+            kdf: Kdf::Sha256,
+            salt: Vec::new(),
+            nonce: CipherSuite::Aes256Gcm.generate_nonce(),
+            commitment: commitment1,
+            padding: Padding::None,
+            wrapped_key: None,
+            recipients: Vec::new(),
+            encrypted_text: Vec::new(),
+        };
+        let aad = associated_data(&data, id);
+
+        // Two ciphertext blocks. GHASH is linear in the first one with
+        // coefficient H^3, so solve for the block that makes the tags
+        // under both keys equal.
+        let mut ciphertext = vec![0u8; 32];
+        let tag_diff = gcm_tag(key1.as_ref(), &data.nonce, &aad, &ciphertext)
+            ^ gcm_tag(key2.as_ref(), &data.nonce, &aad, &ciphertext);
+        let cube = |h: u128| gf_mul(gf_mul(h, h), h);
+        let coefficient = cube(aes_block(key1.as_ref(), 0)) ^ cube(aes_block(key2.as_ref(), 0));
+        let first_block = gf_mul(tag_diff, gf_inv(coefficient));
+        ciphertext[..16].copy_from_slice(&first_block.to_be_bytes());
+
+        let tag = gcm_tag(key1.as_ref(), &data.nonce, &aad, &ciphertext);
+        assert_eq!(tag, gcm_tag(key2.as_ref(), &data.nonce, &aad, &ciphertext));
+        ciphertext.extend_from_slice(&tag.to_be_bytes());
+        data.encrypted_text = ciphertext;
+
+        // Plain AES-GCM accepts the ciphertext under both keys.
+        for key in [&key1, &key2] {
+            assert!(
+                CipherSuite::Aes256Gcm
+                    .open(key.as_ref(), &data.nonce, &data.encrypted_text, &aad)
+                    .is_ok()
+            );
+        }
+
+        // The commitment only lets the committed key through.
+        assert!(decrypt(&data, "key1", id, &Keyring::default()).is_ok());
+        assert!(matches!(
+            decrypt(&data, "key2", id, &Keyring::default()),
+            Err(CryptoError::KeyCommitment)
+        ));
+    }
 }
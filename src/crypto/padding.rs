@@ -0,0 +1,123 @@
+use crate::crypto::CryptoError;
+
+/// Bucket sizes used by `Padding::Buckets`. Longer plaintexts are padded
+/// to a multiple of the largest bucket.
+const BUCKETS: [usize; 3] = [128, 256, 1024];
+
+/// Marks the end of the plaintext inside the padded buffer
+/// (ISO/IEC 7816-4 style), so unpadding doesn't depend on the scheme.
+const PAD_MARKER: u8 = 0x80;
+
+/// How a plaintext is padded before sealing, so the ciphertext length
+/// doesn't reveal the exact length of the secret.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Padding {
+    /// No padding, the ciphertext length follows the plaintext.
+    None,
+    /// Pads to the next of 128, 256 or 1024 bytes, then to multiples
+    /// of 1024.
+    #[default]
+    Buckets,
+    /// Padmé: leaks O(log log n) bits of the length and adds at most
+    /// 12% overhead.
+    Padme,
+}
+
+impl Padding {
+    /// Parses a scheme from its config name, e.g. `padme`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Padding::None),
+            "buckets" => Some(Padding::Buckets),
+            "padme" => Some(Padding::Padme),
+            _ => None,
+        }
+    }
+
+    /// Appends the end marker and pads `msg` to the scheme's length.
+    pub fn pad(&self, msg: &[u8]) -> Vec<u8> {
+        if *self == Padding::None {
+            return msg.to_vec();
+        }
+        let len = msg.len() + 1;
+        let padded_len = match self {
+            Padding::None => len,
+            Padding::Buckets => bucket_len(len),
+            Padding::Padme => padme_len(len),
+        };
+        let mut padded = Vec::with_capacity(padded_len);
+        padded.extend_from_slice(msg);
+        padded.push(PAD_MARKER);
+        padded.resize(padded_len, 0);
+        padded
+    }
+
+    /// Strips the padding added by `pad` in place.
+    ///
+    /// # Errors
+    /// Errors if the end marker is missing.
+    pub fn unpad(&self, padded: &mut Vec<u8>) -> Result<(), CryptoError> {
+        if *self == Padding::None {
+            return Ok(());
+        }
+        let end = padded
+            .iter()
+            .rposition(|b| *b != 0)
+            .filter(|i| padded[*i] == PAD_MARKER)
+            .ok_or(CryptoError::MalformedEnvelope)?;
+        padded.truncate(end);
+        Ok(())
+    }
+}
+
+fn bucket_len(len: usize) -> usize {
+    let largest = BUCKETS[BUCKETS.len() - 1];
+    BUCKETS
+        .into_iter()
+        .find(|bucket| len <= *bucket)
+        .unwrap_or_else(|| len.div_ceil(largest) * largest)
+}
+
+/// Rounds `len` up so only the top log2(log2(len)) + 1 bits of it
+/// are kept, see <https://lbarman.ch/blog/padme/>.
+fn padme_len(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+    let e = len.ilog2();
+    let s = e.ilog2() + 1;
+    let mask = (1usize << (e - s)) - 1;
+    (len + mask) & !mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pad_unpad() {
+        for padding in [Padding::None, Padding::Buckets, Padding::Padme] {
+            for len in [0, 1, 127, 128, 1000, 5000] {
+                let msg = vec![0u8; len];
+                let mut padded = padding.pad(&msg);
+                padding.unpad(&mut padded).expect("unpad failed");
+                assert_eq!(msg, padded, "padding: {:?}, len: {}", padding, len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_padded_lengths() {
+        assert_eq!(128, Padding::Buckets.pad(b"https://example.com").len());
+        assert_eq!(256, Padding::Buckets.pad(&[1u8; 128]).len());
+        assert_eq!(3072, Padding::Buckets.pad(&[1u8; 2048]).len());
+        assert_eq!(1024 + 64, Padding::Padme.pad(&[1u8; 1024]).len());
+        assert_eq!(9, Padding::None.pad(b"abcdefghi").len());
+    }
+
+    #[test]
+    fn test_unpad_rejects_missing_marker() {
+        assert!(Padding::Padme.unpad(&mut vec![1, 2, 0, 0]).is_err());
+        assert!(Padding::Buckets.unpad(&mut vec![0, 0]).is_err());
+    }
+}
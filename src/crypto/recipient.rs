@@ -0,0 +1,169 @@
+use aes_gcm::aead::OsRng;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::crypto::{CipherSuite, CryptoError, KEY_LEN, SecretKey};
+
+/// Length in bytes of an X25519 public key.
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// HKDF info for the key a stanza's file key is wrapped with.
+const STANZA_KEY_INFO: &[u8] = b"cipherlink x25519 recipient v1";
+
+/// Every stanza is sealed under a fresh key, so a fixed nonce is safe.
+const STANZA_NONCE: [u8; 12] = [0; 12];
+
+/// A record's file key wrapped to one recipient, age style: an
+/// ephemeral X25519 key agreement with the recipient's public key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecipientStanza {
+    /// Public half of the ephemeral key used for this recipient.
+    pub ephemeral_public: [u8; PUBLIC_KEY_LEN],
+    /// The ChaCha20-Poly1305 sealed file key.
+    pub wrapped_key: Vec<u8>,
+}
+
+/// Parses a base64 encoded X25519 public key.
+///
+/// # Errors
+/// Errors if the key is not 32 base64 encoded bytes.
+pub fn parse_public_key(encoded: &str) -> Result<PublicKey, String> {
+    let decoded: [u8; PUBLIC_KEY_LEN] = STANDARD
+        .decode(encoded)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("recipient '{}' must be 32 base64 encoded bytes", encoded))?;
+    Ok(PublicKey::from(decoded))
+}
+
+/// Parses a base64 encoded X25519 private key.
+///
+/// # Errors
+/// Errors if the key is not 32 base64 encoded bytes.
+pub fn parse_identity(encoded: &str) -> Result<StaticSecret, String> {
+    let decoded = Zeroizing::new(STANDARD.decode(encoded).unwrap_or_default());
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    if decoded.len() != KEY_LEN {
+        return Err("identity must be 32 base64 encoded bytes".into());
+    }
+    key.copy_from_slice(&decoded);
+    Ok(StaticSecret::from(*key))
+}
+
+/// Wraps the file key to a recipient. The record id is authenticated so
+/// stanzas can't be moved between records.
+///
+/// # Errors
+/// Errors if the public key is a low order point or sealing fails.
+pub fn wrap(
+    file_key: &SecretKey,
+    recipient: &PublicKey,
+    id: &str,
+) -> Result<RecipientStanza, CryptoError> {
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(recipient);
+    if !shared.was_contributory() {
+        return Err(CryptoError::KeyDerivation);
+    }
+    let wrap_key = stanza_key(&shared, &ephemeral_public, recipient);
+    let wrapped_key = CipherSuite::ChaCha20Poly1305.seal(
+        wrap_key.as_ref(),
+        &STANZA_NONCE,
+        file_key.as_ref(),
+        id.as_bytes(),
+    )?;
+    Ok(RecipientStanza {
+        ephemeral_public: ephemeral_public.to_bytes(),
+        wrapped_key,
+    })
+}
+
+/// Unwraps the file key from the first stanza addressed to `identity`.
+///
+/// # Errors
+/// Errors if no stanza opens with the identity.
+pub fn unwrap(
+    stanzas: &[RecipientStanza],
+    identity: &StaticSecret,
+    id: &str,
+) -> Result<SecretKey, CryptoError> {
+    let public = PublicKey::from(identity);
+    for stanza in stanzas {
+        let ephemeral_public = PublicKey::from(stanza.ephemeral_public);
+        let shared = identity.diffie_hellman(&ephemeral_public);
+        if !shared.was_contributory() {
+            continue;
+        }
+        let wrap_key = stanza_key(&shared, &ephemeral_public, &public);
+        let Ok(opened) = CipherSuite::ChaCha20Poly1305.open(
+            wrap_key.as_ref(),
+            &STANZA_NONCE,
+            &stanza.wrapped_key,
+            id.as_bytes(),
+        ) else {
+            continue;
+        };
+        let opened = Zeroizing::new(opened);
+        if opened.len() == KEY_LEN {
+            let mut file_key = SecretKey::default();
+            file_key.copy_from_slice(&opened);
+            return Ok(file_key);
+        }
+    }
+    Err(CryptoError::NoMatchingRecipient)
+}
+
+/// Derives a stanza's wrapping key from the shared secret, salted with
+/// both public keys.
+fn stanza_key(shared: &SharedSecret, ephemeral: &PublicKey, recipient: &PublicKey) -> SecretKey {
+    let mut salt = [0u8; PUBLIC_KEY_LEN * 2];
+    salt[..PUBLIC_KEY_LEN].copy_from_slice(ephemeral.as_bytes());
+    salt[PUBLIC_KEY_LEN..].copy_from_slice(recipient.as_bytes());
+    let mut key = SecretKey::default();
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(STANZA_KEY_INFO, key.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_unwrap() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let bob = StaticSecret::random_from_rng(OsRng);
+        let file_key = SecretKey::new([7u8; KEY_LEN]);
+        let stanzas = vec![
+            wrap(&file_key, &PublicKey::from(&alice), "id").unwrap(),
+            wrap(&file_key, &PublicKey::from(&bob), "id").unwrap(),
+        ];
+
+        assert_eq!(*file_key, *unwrap(&stanzas, &bob, "id").unwrap());
+        assert!(unwrap(&stanzas, &alice, "other").is_err());
+        assert!(matches!(
+            unwrap(&stanzas, &StaticSecret::random_from_rng(OsRng), "id"),
+            Err(CryptoError::NoMatchingRecipient)
+        ));
+    }
+
+    #[test]
+    fn test_rejects_low_order_public_key() {
+        let file_key = SecretKey::default();
+        assert!(wrap(&file_key, &PublicKey::from([0u8; PUBLIC_KEY_LEN]), "id").is_err());
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let identity = parse_identity("AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=").unwrap();
+        let public = STANDARD.encode(PublicKey::from(&identity).as_bytes());
+        assert!(parse_public_key(&public).is_ok());
+        assert!(parse_public_key("c2hvcnQ=").is_err());
+        assert!(parse_identity("not base64").is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use zeroize::Zeroizing;
+
+use crate::crypto::CryptoError;
+
+/// One Shamir share of a secret: the polynomials evaluated at `index`.
+pub struct Share {
+    /// The x coordinate, never 0 since that is where the secret lives.
+    pub index: u8,
+    /// One y coordinate per byte of the secret.
+    pub value: Zeroizing<Vec<u8>>,
+}
+
+impl Share {
+    /// Encodes the share as base64 of the index followed by the value.
+    pub fn encode(&self) -> String {
+        let mut bytes = Zeroizing::new(Vec::with_capacity(1 + self.value.len()));
+        bytes.push(self.index);
+        bytes.extend_from_slice(&self.value);
+        STANDARD.encode(bytes.as_slice())
+    }
+
+    /// Parses a share produced by `encode`.
+    ///
+    /// # Errors
+    /// Errors if the share is not base64 or has a zero index.
+    pub fn parse(encoded: &str) -> Result<Self, String> {
+        let decoded = Zeroizing::new(STANDARD.decode(encoded).unwrap_or_default());
+        match decoded.split_first() {
+            Some((&index, value)) if index != 0 && !value.is_empty() => Ok(Share {
+                index,
+                value: Zeroizing::new(value.to_vec()),
+            }),
+            _ => Err("share is not valid".into()),
+        }
+    }
+}
+
+/// Splits `secret` into `shares` shares, any `threshold` of which
+/// recover it. Works bytewise over GF(2^8), so shares are as long as the
+/// secret plus the index.
+///
+/// # Errors
+/// Errors unless 1 <= threshold <= shares.
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Result<Vec<Share>, CryptoError> {
+    if threshold == 0 || threshold > shares {
+        return Err(CryptoError::InvalidShares);
+    }
+    let mut out: Vec<Share> = (1..=shares)
+        .map(|index| Share {
+            index,
+            value: Zeroizing::new(Vec::with_capacity(secret.len())),
+        })
+        .collect();
+
+    // coefficients[0] is the secret byte, the rest are random.
+    let mut coefficients = Zeroizing::new(vec![0u8; threshold as usize]);
+    for byte in secret {
+        coefficients[0] = *byte;
+        OsRng.fill_bytes(&mut coefficients[1..]);
+        for share in out.iter_mut() {
+            // Horner's rule, highest degree first.
+            let y = coefficients
+                .iter()
+                .rev()
+                .fold(0, |acc, c| gf_mul(acc, share.index) ^ c);
+            share.value.push(y);
+        }
+    }
+    Ok(out)
+}
+
+/// Recovers the secret from at least `threshold` shares by Lagrange
+/// interpolation at 0. Fewer shares yield an unrelated value, which
+/// the record's key commitment then rejects.
+///
+/// # Errors
+/// Errors if there are no shares, indices repeat, or the values differ
+/// in length.
+pub fn combine(shares: &[Share]) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    let Some(first) = shares.first() else {
+        return Err(CryptoError::InvalidShares);
+    };
+    let len = first.value.len();
+    for (i, share) in shares.iter().enumerate() {
+        if share.index == 0
+            || share.value.len() != len
+            || shares[..i].iter().any(|other| other.index == share.index)
+        {
+            return Err(CryptoError::InvalidShares);
+        }
+    }
+
+    let mut secret = Zeroizing::new(vec![0u8; len]);
+    for share in shares {
+        // The Lagrange basis polynomial of this share, evaluated at 0.
+        let mut basis = 1;
+        for other in shares.iter().filter(|other| other.index != share.index) {
+            basis = gf_mul(basis, gf_div(other.index, other.index ^ share.index));
+        }
+        for (byte, y) in secret.iter_mut().zip(share.value.iter()) {
+            *byte ^= gf_mul(*y, basis);
+        }
+    }
+    Ok(secret)
+}
+
+/// Multiplication in GF(2^8) modulo the AES polynomial, without
+/// secret dependent branches.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    product
+}
+
+/// Division in GF(2^8). `b` is a difference of distinct indices, so it
+/// is never 0.
+fn gf_div(a: u8, b: u8) -> u8 {
+    // b^254 is the inverse of b.
+    let mut inverse = 1;
+    for _ in 0..254 {
+        inverse = gf_mul(inverse, b);
+    }
+    gf_mul(a, inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_combine() {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let shares = split(secret, 2, 3).expect("split failed");
+        assert_eq!(3, shares.len());
+
+        for pair in [[0, 1], [0, 2], [1, 2]] {
+            let subset: Vec<Share> = pair
+                .iter()
+                .map(|i| Share::parse(&shares[*i].encode()).unwrap())
+                .collect();
+            assert_eq!(secret.to_vec(), *combine(&subset).unwrap());
+        }
+        // One share alone reveals nothing about the secret.
+        assert_ne!(secret.to_vec(), *combine(&shares[..1]).unwrap());
+        assert_eq!(secret.to_vec(), *combine(&shares).unwrap());
+    }
+
+    #[test]
+    fn test_rejects_invalid() {
+        assert!(split(b"abc", 0, 3).is_err());
+        assert!(split(b"abc", 4, 3).is_err());
+        let shares = split(b"abc", 2, 3).expect("split failed");
+        let duplicate = [
+            Share::parse(&shares[0].encode()).unwrap(),
+            Share::parse(&shares[0].encode()).unwrap(),
+        ];
+        assert!(combine(&duplicate).is_err());
+        assert!(Share::parse("AA==").is_err());
+    }
+
+    #[test]
+    fn test_gf_arithmetic() {
+        // From FIPS-197 section 4.2.
+        assert_eq!(0xc1, gf_mul(0x57, 0x83));
+        for b in 1..=255 {
+            assert_eq!(1, gf_mul(b, gf_div(1, b)));
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use zeroize::Zeroizing;
+
+use crate::crypto::{CipherSuite, CryptoError, SecretKey};
+
+/// Plaintext bytes per chunk. The last chunk may be shorter, or empty
+/// for an empty file.
+pub const CHUNK_LEN: usize = 64 * 1024;
+
+/// Nonce bytes taken by the big-endian chunk counter and the flag
+/// marking the last chunk. The rest is the stream's nonce prefix.
+const COUNTER_LEN: usize = 5;
+
+/// Flag byte of the last chunk's nonce.
+const LAST_CHUNK: u8 = 1;
+
+/// Seals a file chunk by chunk with the STREAM construction (Hoang,
+/// Reyhanitabar, Rogaway and Vizár), so it never has to be buffered
+/// whole. Every chunk's nonce holds its index and whether it is the
+/// last, so chunks can't be reordered, dropped or appended.
+pub struct StreamSealer {
+    suite: CipherSuite,
+    key: SecretKey,
+    nonce_prefix: Vec<u8>,
+    aad: Vec<u8>,
+    counter: u32,
+    finished: bool,
+}
+
+impl StreamSealer {
+    /// Starts a stream under a fresh random key and nonce prefix.
+    /// `aad` is authenticated with every chunk, e.g. the record id.
+    pub fn new(suite: CipherSuite, aad: &[u8]) -> Self {
+        let mut key = SecretKey::default();
+        OsRng.fill_bytes(key.as_mut());
+        let mut nonce_prefix = vec![0u8; suite.nonce_len() - COUNTER_LEN];
+        OsRng.fill_bytes(&mut nonce_prefix);
+        StreamSealer {
+            suite,
+            key,
+            nonce_prefix,
+            aad: aad.to_vec(),
+            counter: 0,
+            finished: false,
+        }
+    }
+
+    /// The stream's key, needed to open it.
+    pub fn key(&self) -> &SecretKey {
+        &self.key
+    }
+
+    /// The stream's nonce prefix, needed to open it.
+    pub fn nonce_prefix(&self) -> &[u8] {
+        &self.nonce_prefix
+    }
+
+    /// Seals the next chunk, `last` for the final one.
+    ///
+    /// # Errors
+    /// Errors if the last chunk was already sealed, there are too many
+    /// chunks or sealing fails.
+    pub fn seal_chunk(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, CryptoError> {
+        if self.finished {
+            return Err(CryptoError::Aead);
+        }
+        let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+        let sealed = self
+            .suite
+            .seal(self.key.as_ref(), &nonce, chunk, &self.aad)?;
+        self.counter = self.counter.checked_add(1).ok_or(CryptoError::Aead)?;
+        self.finished = last;
+        Ok(sealed)
+    }
+}
+
+/// Opens a stream sealed by `StreamSealer`, chunk by chunk in order.
+pub struct StreamOpener {
+    suite: CipherSuite,
+    key: SecretKey,
+    nonce_prefix: Vec<u8>,
+    aad: Vec<u8>,
+    counter: u32,
+    finished: bool,
+}
+
+impl StreamOpener {
+    /// Prepares to open a stream with the key and nonce prefix it was
+    /// sealed with.
+    ///
+    /// # Errors
+    /// Errors if the nonce prefix doesn't fit the suite.
+    pub fn new(
+        suite: CipherSuite,
+        key: SecretKey,
+        nonce_prefix: Vec<u8>,
+        aad: &[u8],
+    ) -> Result<Self, CryptoError> {
+        if nonce_prefix.len() + COUNTER_LEN != suite.nonce_len() {
+            return Err(CryptoError::MalformedEnvelope);
+        }
+        Ok(StreamOpener {
+            suite,
+            key,
+            nonce_prefix,
+            aad: aad.to_vec(),
+            counter: 0,
+            finished: false,
+        })
+    }
+
+    /// Opens the next chunk, `last` for the one the caller expects to
+    /// be final. Returns the plaintext, wiped from memory once dropped.
+    ///
+    /// # Errors
+    /// Errors if the last chunk was already opened, or the chunk
+    /// doesn't authenticate, e.g. it is out of order or the stream was
+    /// cut short.
+    pub fn open_chunk(
+        &mut self,
+        chunk: &[u8],
+        last: bool,
+    ) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+        if self.finished {
+            return Err(CryptoError::Aead);
+        }
+        let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+        let plain_text = self
+            .suite
+            .open(self.key.as_ref(), &nonce, chunk, &self.aad)?;
+        self.counter = self.counter.checked_add(1).ok_or(CryptoError::Aead)?;
+        self.finished = last;
+        Ok(Zeroizing::new(plain_text))
+    }
+}
+
+/// The nonce prefix followed by the chunk counter and last chunk flag.
+fn chunk_nonce(nonce_prefix: &[u8], counter: u32, last: bool) -> Vec<u8> {
+    let mut nonce = Vec::with_capacity(nonce_prefix.len() + COUNTER_LEN);
+    nonce.extend_from_slice(nonce_prefix);
+    nonce.extend_from_slice(&counter.to_be_bytes());
+    nonce.push(if last { LAST_CHUNK } else { 0 });
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opener(sealer: &StreamSealer, aad: &[u8]) -> StreamOpener {
+        StreamOpener::new(
+            sealer.suite,
+            sealer.key().clone(),
+            sealer.nonce_prefix().to_vec(),
+            aad,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_stream_round_trip() {
+        for suite in [CipherSuite::Aes256Gcm, CipherSuite::XChaCha20Poly1305] {
+            let mut sealer = StreamSealer::new(suite, b"id");
+            let first = sealer.seal_chunk(b"hello ", false).unwrap();
+            let second = sealer.seal_chunk(b"world", true).unwrap();
+            assert!(sealer.seal_chunk(b"more", true).is_err());
+
+            let mut opener = opener(&sealer, b"id");
+            assert_eq!(
+                b"hello ",
+                opener.open_chunk(&first, false).unwrap().as_slice()
+            );
+            assert_eq!(
+                b"world",
+                opener.open_chunk(&second, true).unwrap().as_slice()
+            );
+        }
+    }
+
+    #[test]
+    fn test_stream_tampering() {
+        let mut sealer = StreamSealer::new(CipherSuite::Aes256Gcm, b"id");
+        let first = sealer.seal_chunk(b"hello ", false).unwrap();
+        let second = sealer.seal_chunk(b"world", true).unwrap();
+
+        // Cut short: the first chunk was not sealed as the last.
+        assert!(opener(&sealer, b"id").open_chunk(&first, true).is_err());
+        // Reordered.
+        assert!(opener(&sealer, b"id").open_chunk(&second, false).is_err());
+        // Moved to another record.
+        assert!(opener(&sealer, b"other").open_chunk(&first, false).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use aes_gcm::{
+    Aes256Gcm,
+    aead::{Aead, KeyInit, OsRng, Payload, rand_core::RngCore},
+};
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use sha2::digest::generic_array::GenericArray;
+
+use crate::crypto::CryptoError;
+
+/// The AEAD used to seal a record. All suites take a 256-bit key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CipherSuite {
+    /// Fastest on hosts with AES-NI.
+    #[default]
+    Aes256Gcm,
+    /// Constant time without AES hardware support.
+    ChaCha20Poly1305,
+    /// ChaCha20-Poly1305 with a 192-bit nonce, safe to pick at random
+    /// for any number of records.
+    XChaCha20Poly1305,
+    /// Nonce-misuse resistant: a repeated nonce only leaks whether two
+    /// plaintexts were equal.
+    Aes256GcmSiv,
+}
+
+impl CipherSuite {
+    /// Parses a suite from its config name, e.g. `chacha20-poly1305`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "aes-256-gcm" => Some(CipherSuite::Aes256Gcm),
+            "chacha20-poly1305" => Some(CipherSuite::ChaCha20Poly1305),
+            "xchacha20-poly1305" => Some(CipherSuite::XChaCha20Poly1305),
+            "aes-256-gcm-siv" => Some(CipherSuite::Aes256GcmSiv),
+            _ => None,
+        }
+    }
+
+    /// Length in bytes of the suite's nonce.
+    pub fn nonce_len(&self) -> usize {
+        match self {
+            CipherSuite::XChaCha20Poly1305 => 24,
+            _ => 12,
+        }
+    }
+
+    /// Generates a random nonce of the right length.
+    pub fn generate_nonce(&self) -> Vec<u8> {
+        let mut nonce = vec![0u8; self.nonce_len()];
+        OsRng.fill_bytes(&mut nonce);
+        nonce
+    }
+
+    /// Encrypts and authenticates `msg`, also authenticating `aad`.
+    ///
+    /// # Errors
+    /// Errors if the key or nonce have the wrong length or sealing fails.
+    pub fn seal(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        msg: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            CipherSuite::Aes256Gcm => seal_with::<Aes256Gcm>(self, key, nonce, msg, aad),
+            CipherSuite::ChaCha20Poly1305 => {
+                seal_with::<ChaCha20Poly1305>(self, key, nonce, msg, aad)
+            }
+            CipherSuite::XChaCha20Poly1305 => {
+                seal_with::<XChaCha20Poly1305>(self, key, nonce, msg, aad)
+            }
+            CipherSuite::Aes256GcmSiv => seal_with::<Aes256GcmSiv>(self, key, nonce, msg, aad),
+        }
+    }
+
+    /// Verifies and decrypts `ciphertext` sealed with the same `aad`.
+    ///
+    /// # Errors
+    /// Errors if the key or nonce have the wrong length or the
+    /// ciphertext doesn't authenticate.
+    pub fn open(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            CipherSuite::Aes256Gcm => open_with::<Aes256Gcm>(self, key, nonce, ciphertext, aad),
+            CipherSuite::ChaCha20Poly1305 => {
+                open_with::<ChaCha20Poly1305>(self, key, nonce, ciphertext, aad)
+            }
+            CipherSuite::XChaCha20Poly1305 => {
+                open_with::<XChaCha20Poly1305>(self, key, nonce, ciphertext, aad)
+            }
+            CipherSuite::Aes256GcmSiv => {
+                open_with::<Aes256GcmSiv>(self, key, nonce, ciphertext, aad)
+            }
+        }
+    }
+}
+
+fn seal_with<C: Aead + KeyInit>(
+    suite: &CipherSuite,
+    key: &[u8],
+    nonce: &[u8],
+    msg: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    // from_slice panics on a length mismatch.
+    if nonce.len() != suite.nonce_len() {
+        return Err(CryptoError::MalformedEnvelope);
+    }
+    let cipher = C::new_from_slice(key).map_err(|_| CryptoError::Aead)?;
+    cipher
+        .encrypt(GenericArray::from_slice(nonce), Payload { msg, aad })
+        .map_err(|_| CryptoError::Aead)
+}
+
+fn open_with<C: Aead + KeyInit>(
+    suite: &CipherSuite,
+    key: &[u8],
+    nonce: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    // from_slice panics on a length mismatch.
+    if nonce.len() != suite.nonce_len() {
+        return Err(CryptoError::MalformedEnvelope);
+    }
+    let cipher = C::new_from_slice(key).map_err(|_| CryptoError::Aead)?;
+    cipher
+        .decrypt(
+            GenericArray::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| CryptoError::Aead)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_SUITES: [CipherSuite; 4] = [
+        CipherSuite::Aes256Gcm,
+        CipherSuite::ChaCha20Poly1305,
+        CipherSuite::XChaCha20Poly1305,
+        CipherSuite::Aes256GcmSiv,
+    ];
+
+    #[test]
+    fn test_seal_open() {
+        let key = [9u8; 32];
+        for suite in ALL_SUITES {
+            let nonce = suite.generate_nonce();
+            let sealed = suite
+                .seal(&key, &nonce, b"abc", b"aad")
+                .expect("seal failed");
+            let got = suite
+                .open(&key, &nonce, &sealed, b"aad")
+                .expect("open failed");
+            assert_eq!(b"abc".to_vec(), got, "suite: {:?}", suite);
+            assert!(suite.open(&key, &nonce, &sealed, b"other").is_err());
+            assert!(suite.open(&key, &nonce[1..], &sealed, b"aad").is_err());
+        }
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(
+            Some(CipherSuite::XChaCha20Poly1305),
+            CipherSuite::from_name("xchacha20-poly1305")
+        );
+        assert_eq!(None, CipherSuite::from_name("rot13"));
+    }
+}
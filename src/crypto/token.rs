@@ -0,0 +1,43 @@
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+/// Random bytes in a management token.
+const TOKEN_LEN: usize = 32;
+
+/// Generates a management token, returning it base64url encoded and
+/// the hash to store. Only the link's creator ever sees the token.
+pub fn generate() -> (String, Vec<u8>) {
+    let mut bytes = Zeroizing::new([0u8; TOKEN_LEN]);
+    OsRng.fill_bytes(bytes.as_mut());
+    let token = URL_SAFE_NO_PAD.encode(bytes.as_ref());
+    let hash = hash(&token);
+    (token, hash)
+}
+
+/// Hashes a token for storage. Tokens are random, so unlike user keys
+/// they don't need a slow KDF.
+pub fn hash(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// Checks a token against a stored hash in constant time.
+pub fn verify(token: &str, stored_hash: &[u8]) -> bool {
+    hash(token).ct_eq(stored_hash).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let (token, stored_hash) = generate();
+        assert!(verify(&token, &stored_hash));
+        assert!(!verify("wrong", &stored_hash));
+        assert!(!verify(&token, &[]));
+        assert_ne!(token, generate().0);
+    }
+}
@@ -6,10 +6,12 @@ use aws_sdk_dynamodb::{
     config::Credentials,
     types::{
         AttributeDefinition, AttributeValue, KeySchemaElement, KeyType, ProvisionedThroughput,
-        ScalarAttributeType,
+        ReturnValue, ScalarAttributeType, TimeToLiveSpecification,
     },
 };
 
+use crate::error::CipherLinkError;
+
 #[derive(Clone)]
 pub struct DynamoDBClient {
     client: Client,
@@ -32,8 +34,15 @@ pub async fn init(url: &str, region: &str) -> DynamoDBClient {
 }
 
 impl DynamoDBClient {
-    /// initialize a tablee in the db.
-    pub async fn init_table(&self, table_name: &str, attribute_name: &str) -> Result<(), Error> {
+    /// initialize a tablee in the db, with TTL enabled on the numeric
+    /// `ttl_attribute`. DynamoDB deletes expired items lazily, up to a
+    /// few days late, so readers still have to check it.
+    pub async fn init_table(
+        &self,
+        table_name: &str,
+        attribute_name: &str,
+        ttl_attribute: &str,
+    ) -> Result<(), Error> {
         self.client
             .create_table()
             .table_name(table_name)
@@ -60,6 +69,18 @@ impl DynamoDBClient {
             )
             .send()
             .await?;
+        self.client
+            .update_time_to_live()
+            .table_name(table_name)
+            .time_to_live_specification(
+                TimeToLiveSpecification::builder()
+                    .attribute_name(ttl_attribute)
+                    .enabled(true)
+                    .build()
+                    .unwrap(),
+            )
+            .send()
+            .await?;
         Ok(())
     }
 
@@ -84,7 +105,7 @@ impl DynamoDBClient {
         table_name: &str,
         key: &str,
         val: &str,
-    ) -> Result<HashMap<String, AttributeValue>, String> {
+    ) -> Result<HashMap<String, AttributeValue>, CipherLinkError> {
         let response = self
             .client
             .get_item()
@@ -92,29 +113,247 @@ impl DynamoDBClient {
             .key(key, AttributeValue::S(val.to_string()))
             .send()
             .await
-            .map_err(|e| format!("DynamoDB get_item failed: {}", e))?;
+            .map_err(|e| {
+                CipherLinkError::Unavailable(format!("DynamoDB get_item failed: {}", e))
+            })?;
 
-        response
-            .item
-            .ok_or_else(|| format!("Item not found for: {}", val))
+        response.item.ok_or_else(|| CipherLinkError::not_found(val))
     }
 
     /// delete an item from the db.
-    pub async fn delete(&self, table: &str, key: &str, value: &str) -> Result<(), String> {
+    #[allow(dead_code)]
+    pub async fn delete(&self, table: &str, key: &str, value: &str) -> Result<(), CipherLinkError> {
         self.client
             .delete_item()
             .table_name(table)
             .key(key, AttributeValue::S(value.into()))
             .send()
             .await
-            .map_err(|e| format!("Failed to delete item: {}", e))?;
+            .map_err(|e| CipherLinkError::Unavailable(format!("Failed to delete item: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// delete an item and return it, in one conditional call, so two
+    /// callers racing for the same item can't both get it.
+    pub async fn take(
+        &self,
+        table: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<HashMap<String, AttributeValue>, CipherLinkError> {
+        let response = self
+            .client
+            .delete_item()
+            .table_name(table)
+            .key(key, AttributeValue::S(value.into()))
+            .condition_expression("attribute_exists(#k)")
+            .expression_attribute_names("#k", key)
+            .return_values(ReturnValue::AllOld)
+            .send()
+            .await
+            .map_err(|e| {
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception())
+                {
+                    CipherLinkError::not_found(value)
+                } else {
+                    CipherLinkError::Unavailable(format!("Failed to take item: {}", e))
+                }
+            })?;
+
+        response
+            .attributes
+            .ok_or_else(|| CipherLinkError::not_found(value))
+    }
+
+    /// decrement a numeric attribute of an item in one conditional
+    /// update, only while it stays above zero. Returns the new value,
+    /// or None if the item is gone, has no such attribute or is at 1,
+    /// in which case the caller deletes it instead.
+    pub async fn decrement(
+        &self,
+        table: &str,
+        key: &str,
+        value: &str,
+        attribute: &str,
+    ) -> Result<Option<u32>, CipherLinkError> {
+        let response = self
+            .client
+            .update_item()
+            .table_name(table)
+            .key(key, AttributeValue::S(value.into()))
+            .update_expression("SET #a = #a - :one")
+            .condition_expression("#a > :one")
+            .expression_attribute_names("#a", attribute)
+            .expression_attribute_values(":one", AttributeValue::N("1".into()))
+            .return_values(ReturnValue::UpdatedNew)
+            .send()
+            .await;
+        let output = match response {
+            Ok(output) => output,
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(CipherLinkError::Unavailable(format!(
+                    "Failed to update item: {}",
+                    e
+                )));
+            }
+        };
+        match output.attributes.as_ref().and_then(|a| a.get(attribute)) {
+            Some(AttributeValue::N(n)) => n
+                .parse()
+                .map(Some)
+                .map_err(|_| CipherLinkError::Corrupt(format!("Invalid '{}'", attribute))),
+            _ => Err(CipherLinkError::Corrupt(format!("Missing '{}'", attribute))),
+        }
+    }
+
+    /// increment a numeric attribute of an item in one conditional
+    /// update, only while it stays below `limit`. A missing attribute
+    /// counts as 0. Returns false if the item is gone or the attribute
+    /// is already at the limit.
+    pub async fn increment_below(
+        &self,
+        table: &str,
+        key: &str,
+        value: &str,
+        attribute: &str,
+        limit: u32,
+    ) -> Result<bool, CipherLinkError> {
+        let response = self
+            .client
+            .update_item()
+            .table_name(table)
+            .key(key, AttributeValue::S(value.into()))
+            .update_expression("SET #a = if_not_exists(#a, :zero) + :one")
+            .condition_expression(
+                "attribute_exists(#k) AND (attribute_not_exists(#a) OR #a < :limit)",
+            )
+            .expression_attribute_names("#k", key)
+            .expression_attribute_names("#a", attribute)
+            .expression_attribute_values(":zero", AttributeValue::N("0".into()))
+            .expression_attribute_values(":one", AttributeValue::N("1".into()))
+            .expression_attribute_values(":limit", AttributeValue::N(limit.to_string()))
+            .send()
+            .await;
+        match response {
+            Ok(_) => Ok(true),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(CipherLinkError::Unavailable(format!(
+                "Failed to update item: {}",
+                e
+            ))),
+        }
+    }
+
+    /// set an attribute of an item to `new` in one conditional update,
+    /// only if it still holds `current`.
+    pub async fn swap_attribute(
+        &self,
+        table: &str,
+        key: &str,
+        value: &str,
+        attribute: &str,
+        current: AttributeValue,
+        new: AttributeValue,
+    ) -> Result<(), CipherLinkError> {
+        self.client
+            .update_item()
+            .table_name(table)
+            .key(key, AttributeValue::S(value.into()))
+            .update_expression("SET #a = :new")
+            .condition_expression("#a = :current")
+            .expression_attribute_names("#a", attribute)
+            .expression_attribute_values(":current", current)
+            .expression_attribute_values(":new", new)
+            .send()
+            .await
+            .map_err(|e| {
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception())
+                {
+                    CipherLinkError::not_found(value)
+                } else {
+                    CipherLinkError::Unavailable(format!("Failed to update item: {}", e))
+                }
+            })?;
+        Ok(())
+    }
 
+    /// set attributes of an item, only if it still exists.
+    pub async fn set_attributes(
+        &self,
+        table: &str,
+        key: &str,
+        value: &str,
+        attributes: HashMap<String, AttributeValue>,
+    ) -> Result<(), CipherLinkError> {
+        let mut request = self
+            .client
+            .update_item()
+            .table_name(table)
+            .key(key, AttributeValue::S(value.into()))
+            .condition_expression("attribute_exists(#k)")
+            .expression_attribute_names("#k", key);
+        let mut sets = Vec::new();
+        for (i, (name, attribute)) in attributes.into_iter().enumerate() {
+            sets.push(format!("#a{} = :v{}", i, i));
+            request = request
+                .expression_attribute_names(format!("#a{}", i), name)
+                .expression_attribute_values(format!(":v{}", i), attribute);
+        }
+        request
+            .update_expression(format!("SET {}", sets.join(", ")))
+            .send()
+            .await
+            .map_err(|e| {
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception())
+                {
+                    CipherLinkError::not_found(value)
+                } else {
+                    CipherLinkError::Unavailable(format!("Failed to update item: {}", e))
+                }
+            })?;
         Ok(())
     }
 
+    /// scan returns every item in the table, following pagination.
+    pub async fn scan(
+        &self,
+        table_name: &str,
+    ) -> Result<Vec<HashMap<String, AttributeValue>>, Error> {
+        let mut items = Vec::new();
+        let mut start_key = None;
+        loop {
+            let resp = self
+                .client
+                .scan()
+                .table_name(table_name)
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await?;
+            items.extend(resp.items().iter().cloned());
+            match resp.last_evaluated_key {
+                Some(key) if !key.is_empty() => start_key = Some(key),
+                _ => break,
+            }
+        }
+        Ok(items)
+    }
+
     /// check db is meant to be usd like a PING functionality.
-    /// Not in use in hte app currently.
-    #[allow(dead_code)]
     pub async fn check_db(&self) -> Result<(), Error> {
         self.client.list_tables().send().await?;
         Ok(())
@@ -0,0 +1,148 @@
+use std::fmt;
+
+use crate::{crypto::CryptoError, types::ErrorBody};
+
+/// Errors surfaced to clients. Each maps to an HTTP status and a
+/// stable `code`, so both front-ends answer the same way.
+#[derive(Debug, PartialEq)]
+pub enum CipherLinkError {
+    /// The request is malformed or asks for something out of range.
+    BadRequest(String),
+    /// The key, identity or shares don't open the record.
+    WrongKey(CryptoError),
+    /// The management token is wrong or the link can't be managed.
+    Forbidden(String),
+    /// Too many wrong keys locked the link for now.
+    Locked,
+    /// There is no such record, e.g. it was consumed or revoked.
+    NotFound(String),
+    /// The link passed its expiry.
+    Expired,
+    /// Too many wrong keys deleted the link.
+    Destroyed,
+    /// Sealing or opening failed for a reason other than the key, e.g.
+    /// a master key that is not configured.
+    Crypto(CryptoError),
+    /// A stored record can't be decoded.
+    Corrupt(String),
+    /// The decrypted data is not a valid URL.
+    InvalidUrl,
+    /// An upload is over its size limit.
+    TooLarge(String),
+    /// The store failed or can't be reached.
+    Unavailable(String),
+}
+
+impl CipherLinkError {
+    /// The error for a missing record.
+    pub fn not_found(id: &str) -> Self {
+        CipherLinkError::NotFound(format!("Item not found for: {}", id))
+    }
+
+    /// The HTTP status the error is answered with.
+    pub fn status(&self) -> u16 {
+        match self {
+            CipherLinkError::BadRequest(_) | CipherLinkError::InvalidUrl => 400,
+            CipherLinkError::WrongKey(_)
+            | CipherLinkError::Forbidden(_)
+            | CipherLinkError::Locked => 403,
+            CipherLinkError::NotFound(_) => 404,
+            CipherLinkError::TooLarge(_) => 413,
+            CipherLinkError::Expired | CipherLinkError::Destroyed => 410,
+            CipherLinkError::Crypto(_) | CipherLinkError::Corrupt(_) => 500,
+            CipherLinkError::Unavailable(_) => 503,
+        }
+    }
+
+    /// A short name of the error clients can match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CipherLinkError::BadRequest(_) => "bad_request",
+            CipherLinkError::WrongKey(_) => "wrong_key",
+            CipherLinkError::Forbidden(_) => "forbidden",
+            CipherLinkError::Locked => "locked",
+            CipherLinkError::NotFound(_) => "not_found",
+            CipherLinkError::Expired => "expired",
+            CipherLinkError::Destroyed => "destroyed",
+            CipherLinkError::Crypto(_) => "crypto_error",
+            CipherLinkError::Corrupt(_) => "corrupt_record",
+            CipherLinkError::InvalidUrl => "invalid_url",
+            CipherLinkError::TooLarge(_) => "too_large",
+            CipherLinkError::Unavailable(_) => "unavailable",
+        }
+    }
+
+    /// The JSON body the error is answered with. Server errors only
+    /// get a fixed message, their detail is for the server's log.
+    pub fn body(&self) -> ErrorBody {
+        let error = match self {
+            CipherLinkError::Crypto(_) => "Crypto failed".to_string(),
+            CipherLinkError::Corrupt(_) => "Corrupt record".to_string(),
+            CipherLinkError::Unavailable(_) => "Service unavailable".to_string(),
+            _ => self.to_string(),
+        };
+        ErrorBody {
+            error,
+            code: self.code(),
+        }
+    }
+}
+
+impl fmt::Display for CipherLinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CipherLinkError::BadRequest(msg)
+            | CipherLinkError::Forbidden(msg)
+            | CipherLinkError::NotFound(msg)
+            | CipherLinkError::TooLarge(msg)
+            | CipherLinkError::Unavailable(msg) => f.write_str(msg),
+            CipherLinkError::WrongKey(e) => write!(f, "Decrypt failed: {}", e),
+            CipherLinkError::Locked => f.write_str("Link locked"),
+            CipherLinkError::Expired => f.write_str("Link expired"),
+            CipherLinkError::Destroyed => {
+                f.write_str("Link destroyed after too many failed attempts")
+            }
+            CipherLinkError::Crypto(e) => write!(f, "Crypto failed: {}", e),
+            CipherLinkError::Corrupt(msg) => write!(f, "Corrupt record: {}", msg),
+            CipherLinkError::InvalidUrl => f.write_str("Decrypted data is not a valid URL"),
+        }
+    }
+}
+
+impl std::error::Error for CipherLinkError {}
+
+impl From<CryptoError> for CipherLinkError {
+    /// Blames the caller's key only when it is the likely cause.
+    fn from(e: CryptoError) -> Self {
+        if e.is_wrong_key() {
+            CipherLinkError::WrongKey(e)
+        } else if e == CryptoError::WrongUnlockMethod {
+            CipherLinkError::BadRequest("Wrong unlock method for this record".into())
+        } else {
+            CipherLinkError::Crypto(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_and_body() {
+        let err = CipherLinkError::from(CryptoError::Aead);
+        assert_eq!((403, "wrong_key"), (err.status(), err.code()));
+        let err = CipherLinkError::from(CryptoError::WrongUnlockMethod);
+        assert_eq!((400, "bad_request"), (err.status(), err.code()));
+        let err = CipherLinkError::from(CryptoError::UnknownMasterKey("old".into()));
+        assert_eq!(500, err.status());
+        // The master key id stays in the server's log.
+        assert_eq!("Crypto failed", err.body().error);
+
+        let body = serde_json::to_value(CipherLinkError::Expired.body()).unwrap();
+        assert_eq!(
+            serde_json::json!({"error": "Link expired", "code": "expired"}),
+            body
+        );
+    }
+}
@@ -0,0 +1,513 @@
+use std::sync::Arc;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use super::{get_live, insert, open_record, resolve_attempt_limit, resolve_expiry, run_blocking};
+use crate::{
+    blob::BlobStore,
+    crypto::{
+        CryptoConfig, SecretKey, decrypt, encrypt,
+        stream::{CHUNK_LEN, StreamOpener, StreamSealer},
+    },
+    error::CipherLinkError,
+    store::{Entry, Record, SecretKind, SecretStore, now},
+    types::{EncryptResponse, FileUploadRequest, SecretString},
+};
+
+/// Upper bound for an uploaded file.
+pub const MAX_FILE_LEN: u64 = 50 * 1024 * 1024;
+
+/// Longest file name or content type kept with an upload.
+const MAX_NAME_LEN: usize = 255;
+
+/// How long the chunks of a file can go unwritten before the sweep
+/// takes them for left behind. An upload only stores its record once
+/// the last chunk is written.
+pub const UPLOAD_GRACE_SECS: u64 = 60 * 60;
+
+/// Sent for files uploaded without a usable content type.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// What the record of a file holds: the key to its chunks and what is
+/// needed to hand it out. It is sealed like any other plain text, so
+/// the name and type are as secret as the contents.
+#[derive(Serialize, Deserialize)]
+struct FileManifest {
+    name: String,
+    content_type: String,
+    size: u64,
+    chunks: u32,
+    /// Base64 encoded stream key.
+    key: SecretString,
+    /// Base64 encoded stream nonce prefix.
+    nonce_prefix: String,
+}
+
+/// A file upload in progress. Chunks are sealed and stored as the
+/// bytes arrive, so the file is never buffered whole. The link only
+/// exists once `finish` stored its record, and the chunks of an upload
+/// dropped before that are deleted.
+pub struct FileUpload {
+    blobs: Arc<dyn BlobStore>,
+    id: String,
+    name: String,
+    content_type: String,
+    sealer: StreamSealer,
+    buffer: Zeroizing<Vec<u8>>,
+    chunks: u32,
+    size: u64,
+    finished: bool,
+}
+
+impl FileUpload {
+    /// Starts an upload under a new id. The name and content type are
+    /// the ones the client sent, cleaned up to be safe in headers.
+    pub fn new(
+        blobs: Arc<dyn BlobStore>,
+        crypto_config: &CryptoConfig,
+        name: Option<&str>,
+        content_type: Option<&str>,
+    ) -> Self {
+        let id = uuid::Uuid::new_v4().to_string();
+        let sealer = StreamSealer::new(crypto_config.suite, id.as_bytes());
+        FileUpload {
+            blobs,
+            id,
+            name: file_name(name),
+            content_type: file_content_type(content_type),
+            sealer,
+            buffer: Zeroizing::new(Vec::with_capacity(CHUNK_LEN)),
+            chunks: 0,
+            size: 0,
+            finished: false,
+        }
+    }
+
+    /// Adds the next bytes of the file, sealing and storing every full
+    /// chunk.
+    ///
+    /// # Errors
+    /// Errors if the file grows over `MAX_FILE_LEN`, or sealing or
+    /// storing a chunk fails.
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), CipherLinkError> {
+        self.size += data.len() as u64;
+        if self.size > MAX_FILE_LEN {
+            return Err(CipherLinkError::TooLarge(format!(
+                "Files are limited to {} bytes",
+                MAX_FILE_LEN
+            )));
+        }
+        self.buffer.extend_from_slice(data);
+        // A full chunk is held back until more data arrives, only
+        // `finish` knows which chunk is the last.
+        while self.buffer.len() > CHUNK_LEN {
+            let chunk = Zeroizing::new(self.buffer.drain(..CHUNK_LEN).collect::<Vec<u8>>());
+            self.store_chunk(&chunk, false).await?;
+        }
+        Ok(())
+    }
+
+    /// Seals the last chunk, then stores the record holding the key to
+    /// the chunks, sealed under the request's key like any other link.
+    /// Returns a UUID that is needed for the download.
+    ///
+    /// # Errors
+    /// Errors if the expiry or attempt limit are invalid, or sealing
+    /// or storing fails. The chunks are deleted then.
+    pub async fn finish(
+        mut self,
+        store: &dyn SecretStore,
+        crypto_config: &CryptoConfig,
+        request: FileUploadRequest,
+    ) -> Result<EncryptResponse, CipherLinkError> {
+        let expires_at = resolve_expiry(&request.expiry)?;
+        let attempts = resolve_attempt_limit(request.max_attempts, request.on_max_attempts)?;
+
+        let last = Zeroizing::new(std::mem::take(&mut *self.buffer));
+        self.store_chunk(&last, true).await?;
+
+        let manifest = FileManifest {
+            name: self.name.clone(),
+            content_type: self.content_type.clone(),
+            size: self.size,
+            chunks: self.chunks,
+            key: STANDARD.encode(self.sealer.key().as_ref()).into(),
+            nonce_prefix: STANDARD.encode(self.sealer.nonce_prefix()),
+        };
+        let plain_text = Zeroizing::new(
+            serde_json::to_string(&manifest)
+                .map_err(|e| CipherLinkError::Corrupt(format!("Invalid file manifest: {}", e)))?,
+        );
+        let (key, id, config) = (request.key, self.id.clone(), crypto_config.clone());
+        let encrypted_data = run_blocking(move || encrypt(&plain_text, key.expose(), &id, &config))
+            .await?
+            .map_err(CipherLinkError::Crypto)?;
+
+        let entry = Entry {
+            record: Record::Server(encrypted_data),
+            kind: SecretKind::File,
+            expires_at,
+            views_left: 1,
+            attempts,
+            management_hash: None,
+        };
+        let resp = insert(store, self.id.clone(), entry).await?;
+        self.finished = true;
+        Ok(resp)
+    }
+
+    async fn store_chunk(&mut self, chunk: &[u8], last: bool) -> Result<(), CipherLinkError> {
+        let sealed = self
+            .sealer
+            .seal_chunk(chunk, last)
+            .map_err(CipherLinkError::Crypto)?;
+        self.blobs.put(&self.id, self.chunks, sealed).await?;
+        self.chunks += 1;
+        Ok(())
+    }
+}
+
+impl Drop for FileUpload {
+    fn drop(&mut self) {
+        if !self.finished {
+            delete_later(self.blobs.clone(), self.id.clone());
+        }
+    }
+}
+
+/// A file being downloaded. Its record is already used up, the chunks
+/// are opened one at a time and deleted after the last, or once the
+/// download is dropped halfway.
+pub struct FileDownload {
+    blobs: Arc<dyn BlobStore>,
+    id: String,
+    opener: StreamOpener,
+    chunks: u32,
+    next: u32,
+    done: bool,
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+}
+
+impl FileDownload {
+    /// Returns the next chunk of the file, or None after the last.
+    ///
+    /// # Errors
+    /// Errors if a chunk is missing or doesn't authenticate. The rest
+    /// of the file is deleted then.
+    pub async fn next_chunk(&mut self) -> Result<Option<Zeroizing<Vec<u8>>>, CipherLinkError> {
+        if self.done {
+            return Ok(None);
+        }
+        if self.next == self.chunks {
+            self.done = true;
+            // Chunks left behind can't be opened, the record holding
+            // their key is gone.
+            let _ = self.blobs.delete(&self.id).await;
+            return Ok(None);
+        }
+        let result = self.open_next().await;
+        if result.is_err() {
+            self.done = true;
+            let _ = self.blobs.delete(&self.id).await;
+        }
+        result.map(Some)
+    }
+
+    async fn open_next(&mut self) -> Result<Zeroizing<Vec<u8>>, CipherLinkError> {
+        let chunk = self.blobs.get(&self.id, self.next).await?;
+        let last = self.next + 1 == self.chunks;
+        let plain_text = self.opener.open_chunk(&chunk, last).map_err(|_| {
+            CipherLinkError::Corrupt(format!("Chunk {} of '{}' doesn't open", self.next, self.id))
+        })?;
+        self.next += 1;
+        Ok(plain_text)
+    }
+}
+
+impl Drop for FileDownload {
+    fn drop(&mut self) {
+        if !self.done {
+            delete_later(self.blobs.clone(), self.id.clone());
+        }
+    }
+}
+
+/// file_download_handler opens the record of a file with the key the
+/// upload was sealed under and uses it up, returning the download that
+/// streams the file. Wrong keys count as failed attempts like on
+/// /decrypt.
+///
+/// # Errors
+/// Same as decrypt_handler, or the record is not a file or its
+/// manifest can't be read.
+pub async fn file_download_handler(
+    store: &dyn SecretStore,
+    blobs: Arc<dyn BlobStore>,
+    crypto_config: &CryptoConfig,
+    id: String,
+    key: SecretString,
+) -> Result<FileDownload, CipherLinkError> {
+    let entry = get_live(store, &id).await?;
+    let Record::Server(data) = &entry.record else {
+        return Err(CipherLinkError::BadRequest("Item is not a file".into()));
+    };
+    if entry.kind != SecretKind::File {
+        return Err(CipherLinkError::BadRequest("Item is not a file".into()));
+    }
+    let suite = data.suite;
+
+    let (record_id, keyring) = (id.clone(), crypto_config.keyring.clone());
+    let opened = open_record(store, &id, &entry.record, move |data| {
+        decrypt(data, key.expose(), &record_id, &keyring)
+    })
+    .await;
+    let plain_text = match opened {
+        Ok(plain_text) => Zeroizing::new(plain_text),
+        Err(CipherLinkError::Destroyed) => {
+            let _ = blobs.delete(&id).await;
+            return Err(CipherLinkError::Destroyed);
+        }
+        Err(e) => return Err(e),
+    };
+    let manifest: FileManifest = serde_json::from_str(&plain_text)
+        .map_err(|_| CipherLinkError::Corrupt("Invalid file manifest".into()))?;
+    let stream_key = decode_key(manifest.key.expose())?;
+    let nonce_prefix = STANDARD
+        .decode(&manifest.nonce_prefix)
+        .map_err(|_| CipherLinkError::Corrupt("Invalid file nonce".into()))?;
+    let opener = StreamOpener::new(suite, stream_key, nonce_prefix, id.as_bytes())
+        .map_err(|_| CipherLinkError::Corrupt("Invalid file nonce".into()))?;
+    if manifest.chunks == 0 {
+        return Err(CipherLinkError::Corrupt("File has no chunks".into()));
+    }
+
+    store.consume(&id).await?;
+
+    Ok(FileDownload {
+        blobs,
+        id,
+        opener,
+        chunks: manifest.chunks,
+        next: 0,
+        done: false,
+        name: manifest.name,
+        content_type: manifest.content_type,
+        size: manifest.size,
+    })
+}
+
+/// Deletes the chunks of files whose record is gone or expired, last
+/// written before unix time `before`. Records expire without their
+/// chunks, e.g. by DynamoDB's TTL or a purge of the memory store, so
+/// nothing else deletes them. Returns how many files were deleted.
+///
+/// # Errors
+/// Errors if the store or the blob store can't be read, or a delete
+/// fails.
+pub async fn sweep_files(
+    store: &dyn SecretStore,
+    blobs: &dyn BlobStore,
+    before: u64,
+) -> Result<u32, CipherLinkError> {
+    let mut deleted = 0;
+    for id in blobs.list(before).await? {
+        match store.get(&id).await {
+            Ok(entry) if !entry.is_expired(now()) => continue,
+            Ok(_) | Err(CipherLinkError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+        blobs.delete(&id).await?;
+        deleted += 1;
+    }
+    Ok(deleted)
+}
+
+/// Decodes the base64 stream key of a manifest.
+fn decode_key(encoded: &str) -> Result<SecretKey, CipherLinkError> {
+    let decoded = Zeroizing::new(STANDARD.decode(encoded).unwrap_or_default());
+    let mut key = SecretKey::default();
+    if decoded.len() != key.len() {
+        return Err(CipherLinkError::Corrupt("Invalid file key".into()));
+    }
+    key.copy_from_slice(&decoded);
+    Ok(key)
+}
+
+/// Deletes the chunks of `id` in the background, for uploads and
+/// downloads dropped halfway, e.g. when the client went away.
+fn delete_later(blobs: Arc<dyn BlobStore>, id: String) {
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        handle.spawn(async move {
+            let _ = blobs.delete(&id).await;
+        });
+    }
+}
+
+/// The base name of an uploaded file, limited to printable ASCII so it
+/// can be sent back in a Content-Disposition header.
+fn file_name(name: Option<&str>) -> String {
+    let base = name
+        .unwrap_or_default()
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default();
+    let name: String = base
+        .chars()
+        .take(MAX_NAME_LEN)
+        .map(|c| match c {
+            ' '..='~' if c != '"' => c,
+            _ => '_',
+        })
+        .collect();
+    if name.is_empty() { "file".into() } else { name }
+}
+
+/// The content type of an uploaded file if it is safe to send back,
+/// `application/octet-stream` otherwise.
+fn file_content_type(content_type: Option<&str>) -> String {
+    match content_type {
+        Some(content_type)
+            if !content_type.is_empty()
+                && content_type.len() <= MAX_NAME_LEN
+                && content_type
+                    .chars()
+                    .all(|c| c.is_ascii_graphic() || c == ' ') =>
+        {
+            content_type.to_string()
+        }
+        _ => DEFAULT_CONTENT_TYPE.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{blob::FsBlobStore, crypto::KdfParams, store::MemoryStore, types::Expiry};
+
+    fn test_config() -> CryptoConfig {
+        CryptoConfig {
+            kdf_params: KdfParams {
+                m_cost: 64,
+                t_cost: 1,
+                p_cost: 1,
+            },
+            ..CryptoConfig::default()
+        }
+    }
+
+    fn upload_request(key: &str) -> FileUploadRequest {
+        FileUploadRequest {
+            key: key.to_string().into(),
+            max_attempts: None,
+            on_max_attempts: None,
+            expiry: Expiry::default(),
+        }
+    }
+
+    async fn download_all(download: &mut FileDownload) -> Vec<u8> {
+        let mut file = Vec::new();
+        while let Some(chunk) = download.next_chunk().await.unwrap() {
+            file.extend_from_slice(&chunk);
+        }
+        file
+    }
+
+    #[tokio::test]
+    async fn test_file_is_one_time() {
+        let root = std::env::temp_dir().join(format!("cipherlink-{}", uuid::Uuid::new_v4()));
+        let blobs: Arc<dyn BlobStore> = Arc::new(FsBlobStore::new(&root));
+        let store = MemoryStore::new();
+        let config = test_config();
+
+        // Over two chunks, written in pieces that don't line up with them.
+        let file: Vec<u8> = (0..2 * CHUNK_LEN + 10).map(|i| i as u8).collect();
+        let mut upload = FileUpload::new(
+            blobs.clone(),
+            &config,
+            Some("../notes \"v2\".txt"),
+            Some("text/plain"),
+        );
+        for piece in file.chunks(1000) {
+            upload.write(piece).await.unwrap();
+        }
+        let id = upload
+            .finish(&store, &config, upload_request("music"))
+            .await
+            .unwrap()
+            .id;
+
+        let wrong = file_download_handler(
+            &store,
+            blobs.clone(),
+            &config,
+            id.clone(),
+            "nope".to_string().into(),
+        )
+        .await;
+        assert!(matches!(wrong, Err(CipherLinkError::WrongKey(_))));
+
+        let mut download = file_download_handler(
+            &store,
+            blobs.clone(),
+            &config,
+            id.clone(),
+            "music".to_string().into(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            ("notes _v2_.txt", "text/plain"),
+            (download.name.as_str(), download.content_type.as_str())
+        );
+        assert_eq!(file.len() as u64, download.size);
+        assert_eq!(file, download_all(&mut download).await);
+
+        // Both the record and the chunks are gone.
+        assert!(store.get(&id).await.is_err());
+        assert!(blobs.get(&id, 0).await.is_err());
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_sweep_expired_files() {
+        let root = std::env::temp_dir().join(format!("cipherlink-{}", uuid::Uuid::new_v4()));
+        let blobs: Arc<dyn BlobStore> = Arc::new(FsBlobStore::new(&root));
+        let store = MemoryStore::new();
+        let config = test_config();
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let mut upload = FileUpload::new(blobs.clone(), &config, None, None);
+            upload.write(b"hello").await.unwrap();
+            let mut request = upload_request("music");
+            request.expiry.ttl_seconds = Some(60);
+            let resp = upload.finish(&store, &config, request).await.unwrap();
+            ids.push(resp.id);
+        }
+        store.set_expiry(&ids[0], now() - 1).await.unwrap();
+
+        // Files written since `before` are left for their upload.
+        assert_eq!(Ok(0), sweep_files(&store, blobs.as_ref(), 0).await);
+        assert_eq!(Ok(1), sweep_files(&store, blobs.as_ref(), now() + 1).await);
+        assert!(blobs.get(&ids[0], 0).await.is_err());
+        assert!(blobs.get(&ids[1], 0).await.is_ok());
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_upload_limit() {
+        let root = std::env::temp_dir().join(format!("cipherlink-{}", uuid::Uuid::new_v4()));
+        let blobs: Arc<dyn BlobStore> = Arc::new(FsBlobStore::new(&root));
+        let mut upload = FileUpload::new(blobs, &test_config(), None, None);
+        let err = upload
+            .write(&vec![0u8; MAX_FILE_LEN as usize + 1])
+            .await
+            .unwrap_err();
+        assert_eq!(413, err.status());
+        assert_eq!(DEFAULT_CONTENT_TYPE, upload.content_type);
+    }
+}
@@ -1,69 +1,1115 @@
+use std::sync::{Arc, LazyLock};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use tokio::sync::Semaphore;
+use zeroize::Zeroizing;
+
 use crate::{
-    crypto::{decrypt, encrypt},
-    db::DynamoDBClient,
-    transformer::{encrypt_data_to_item, item_to_encryt_data},
-    types::{EncryptRequest, EncryptResponse, HealthStatus},
+    blob::BlobStore,
+    crypto::{
+        CryptoConfig, CryptoError, EncryptData, decrypt, decrypt_with_identity,
+        decrypt_with_shares, encrypt, encrypt_split, encrypt_to_recipients, recipient,
+        shamir::Share, token,
+    },
+    error::CipherLinkError,
+    store::{
+        self, AttemptLimit, AttemptOutcome, Attempts, Entry, Lockout, Record, SecretKind,
+        SecretStore,
+    },
+    types::{
+        ClientEncryptRequest, DecryptPayload, DecryptResponse, EncryptRequest, EncryptResponse,
+        Expiry, HealthStatus, KeyDecryptRequest, LinkStatus, RecipientEncryptRequest,
+        RetrieveResponse, SecretString, SplitEncryptRequest,
+    },
 };
 
+mod file;
+
+pub use file::{FileUpload, MAX_FILE_LEN, UPLOAD_GRACE_SECS, file_download_handler, sweep_files};
+
+/// Upper bound for a client-side envelope. Plenty for a URL and well
+/// under DynamoDB's item size limit.
+const MAX_CLIENT_CIPHERTEXT_LEN: usize = 16 * 1024;
+
+/// Upper bound for max_views of one record.
+const MAX_VIEWS: u32 = 100;
+
+/// Failed decrypt attempts a record takes unless the request says
+/// otherwise, and the upper bound a request may ask for.
+const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+const MAX_ATTEMPTS: u32 = 100;
+
+/// How long a record stays locked once it reached its attempt limit.
+const LOCKOUT_SECONDS: u64 = 15 * 60;
+
+/// Decryptions and key derivations that run at once. An Argon2id
+/// derivation holds up to the KDF's memory cost, the rest wait for a
+/// permit.
+const MAX_CONCURRENT_KDF: usize = 4;
+
+static KDF_PERMITS: LazyLock<Arc<Semaphore>> =
+    LazyLock::new(|| Arc::new(Semaphore::new(MAX_CONCURRENT_KDF)));
+
+/// Upper bound for recipients of one record, each adds a stanza to the
+/// item.
+const MAX_RECIPIENTS: usize = 16;
+
+/// The page served for zero-knowledge links. It reads the key from the
+/// URL fragment, which browsers never send to the server.
+const OPEN_PAGE: &str = include_str!("open.html");
+
+/// The page that posts a key from the URL fragment to /decrypt/{id},
+/// so it never reaches the server in a URL.
+const UNLOCK_PAGE: &str = include_str!("unlock.html");
+
+/// The page a `text` secret is shown on, `{{secret}}` is replaced with
+/// the escaped secret.
+const SECRET_PAGE: &str = include_str!("secret.html");
+
+/// Authorization scheme a decrypt key is sent with.
+const KEY_AUTH_SCHEME: &str = "Bearer";
+
 /// health_handler is just used to see if one can get a response
-/// from the app.
-pub async fn health_handler() -> HealthStatus {
-    HealthStatus { status: "healthy" }
+/// from the app and it can reach its store.
+pub async fn health_handler(store: &dyn SecretStore) -> HealthStatus {
+    match store.health().await {
+        Ok(()) => HealthStatus { status: "healthy" },
+        Err(_) => HealthStatus {
+            status: "unhealthy",
+        },
+    }
 }
 
 /// encrypt_handler encrypts the data in the request, inserts
-/// it into dynamodb then returns a UUID that is needeed for
-/// decryption.
+/// it into the store then returns a UUID that is needeed for
+/// decryption. The key is stretched with Argon2id using the configured
+/// parameters, the data key is wrapped under the active master key,
+/// and the generated UUID is bound to the ciphertext.
 ///
 /// # Errors
-/// Encryption and inserting to the db can fail.
+/// Encryption and inserting to the store can fail.
 pub async fn encrypt_handler(
-    db_client: &DynamoDBClient,
+    store: &dyn SecretStore,
+    crypto_config: &CryptoConfig,
     encrypt_request: EncryptRequest,
-) -> Result<EncryptResponse, String> {
-    let encrypted_data = encrypt(&encrypt_request.plain_text, &encrypt_request.key)
-        .map_err(|_| "Encryption failed")?;
-
+) -> Result<EncryptResponse, CipherLinkError> {
+    let kind = resolve_kind(encrypt_request.kind, encrypt_request.plain_text.expose())?;
+    let expires_at = resolve_expiry(&encrypt_request.expiry)?;
+    let views_left = resolve_max_views(encrypt_request.max_views)?;
+    let attempts = resolve_attempt_limit(
+        encrypt_request.max_attempts,
+        encrypt_request.on_max_attempts,
+    )?;
     let id = uuid::Uuid::new_v4().to_string();
-    let item = encrypt_data_to_item(&id, &encrypted_data);
-
-    db_client
-        .insert("encryptData", item)
-        .await
-        .map_err(|e| format!("DB insert failed: {}", e))?;
+    let (plain_text, key) = (encrypt_request.plain_text, encrypt_request.key);
+    let (record_id, config) = (id.clone(), crypto_config.clone());
+    let encrypted_data =
+        run_blocking(move || encrypt(plain_text.expose(), key.expose(), &record_id, &config))
+            .await?
+            .map_err(CipherLinkError::Crypto)?;
 
-    Ok(EncryptResponse { id })
+    let entry = Entry {
+        record: Record::Server(encrypted_data),
+        kind,
+        expires_at,
+        views_left,
+        attempts,
+        management_hash: None,
+    };
+    insert(store, id, entry).await
 }
 
 /// decrypt_handler requires the key used for the original
 /// decryption and UUID that was returned when the encrypt handle
 /// was called.
-/// Assuming a valid UUID and key, will return the plaintext.
+/// Assuming a valid UUID and key, will return the plaintext and how
+/// many views are left. The key and decrypted bytes are wiped from
+/// memory once dropped.
 ///
 /// # Errors
 /// Potential failures on the following steps retrieving/deleting
-/// from the db, decoding/transforming the data from the db,
-/// and decryption.
+/// from the store and decryption.
 pub async fn decrypt_handler(
-    db_client: &DynamoDBClient,
+    store: &dyn SecretStore,
+    crypto_config: &CryptoConfig,
     id: String,
-    key: String,
-) -> Result<String, String> {
-    let data = db_client
-        .get("encryptData", "id", &id)
+    key: SecretString,
+) -> Result<DecryptResponse, CipherLinkError> {
+    let (record_id, keyring) = (id.clone(), crypto_config.keyring.clone());
+    decrypt_and_consume(store, &id, move |data| {
+        decrypt(data, key.expose(), &record_id, &keyring)
+    })
+    .await
+}
+
+/// recipient_encrypt_handler encrypts the data in the request to each
+/// recipient's X25519 public key, inserts it into the store then returns
+/// a UUID. Any one recipient's private key decrypts it, no key has to
+/// be shared out of band.
+///
+/// # Errors
+/// A recipient may not be a valid public key, there may be no or too
+/// many recipients, and encryption and inserting to the store can fail.
+pub async fn recipient_encrypt_handler(
+    store: &dyn SecretStore,
+    crypto_config: &CryptoConfig,
+    encrypt_request: RecipientEncryptRequest,
+) -> Result<EncryptResponse, CipherLinkError> {
+    if encrypt_request.recipients.is_empty() || encrypt_request.recipients.len() > MAX_RECIPIENTS {
+        return Err(CipherLinkError::BadRequest(format!(
+            "Between 1 and {} recipients are required",
+            MAX_RECIPIENTS
+        )));
+    }
+    let recipients = encrypt_request
+        .recipients
+        .iter()
+        .map(|r| recipient::parse_public_key(r))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(CipherLinkError::BadRequest)?;
+    let kind = resolve_kind(encrypt_request.kind, encrypt_request.plain_text.expose())?;
+    let expires_at = resolve_expiry(&encrypt_request.expiry)?;
+    let views_left = resolve_max_views(encrypt_request.max_views)?;
+    let attempts = resolve_attempt_limit(
+        encrypt_request.max_attempts,
+        encrypt_request.on_max_attempts,
+    )?;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let encrypted_data = encrypt_to_recipients(
+        encrypt_request.plain_text.expose(),
+        &recipients,
+        &id,
+        crypto_config,
+    )
+    .map_err(CipherLinkError::Crypto)?;
+
+    let entry = Entry {
+        record: Record::Server(encrypted_data),
+        kind,
+        expires_at,
+        views_left,
+        attempts,
+        management_hash: None,
+    };
+    insert(store, id, entry).await
+}
+
+/// recipient_decrypt_handler decrypts a record encrypted to recipients
+/// with one recipient's X25519 private key, then deletes it. The
+/// private key is wiped from memory once dropped.
+///
+/// # Errors
+/// Potential failures on the following steps retrieving/deleting
+/// from the store, parsing the private key and decryption.
+pub async fn recipient_decrypt_handler(
+    store: &dyn SecretStore,
+    crypto_config: &CryptoConfig,
+    id: String,
+    identity: SecretString,
+) -> Result<DecryptResponse, CipherLinkError> {
+    let identity =
+        recipient::parse_identity(identity.expose()).map_err(CipherLinkError::BadRequest)?;
+    let (record_id, keyring) = (id.clone(), crypto_config.keyring.clone());
+    decrypt_and_consume(store, &id, move |data| {
+        decrypt_with_identity(data, &identity, &record_id, &keyring)
+    })
+    .await
+}
+
+/// split_encrypt_handler encrypts the data in the request under a key
+/// split into Shamir shares, inserts it into the store then returns a
+/// UUID and the shares. Any `threshold` of the shares decrypt it. The
+/// shares are not stored, they are only ever in this response.
+///
+/// # Errors
+/// The threshold must be at least 2 and at most the number of shares,
+/// and encryption and inserting to the store can fail.
+pub async fn split_encrypt_handler(
+    store: &dyn SecretStore,
+    crypto_config: &CryptoConfig,
+    encrypt_request: SplitEncryptRequest,
+) -> Result<EncryptResponse, CipherLinkError> {
+    // A single share would just be the key.
+    if encrypt_request.threshold < 2 || encrypt_request.threshold > encrypt_request.shares {
+        return Err(CipherLinkError::BadRequest(
+            "Threshold must be between 2 and the number of shares".into(),
+        ));
+    }
+    let kind = resolve_kind(encrypt_request.kind, encrypt_request.plain_text.expose())?;
+    let expires_at = resolve_expiry(&encrypt_request.expiry)?;
+    let views_left = resolve_max_views(encrypt_request.max_views)?;
+    let attempts = resolve_attempt_limit(
+        encrypt_request.max_attempts,
+        encrypt_request.on_max_attempts,
+    )?;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let (encrypted_data, shares) = encrypt_split(
+        encrypt_request.plain_text.expose(),
+        encrypt_request.threshold,
+        encrypt_request.shares,
+        &id,
+        crypto_config,
+    )
+    .map_err(CipherLinkError::Crypto)?;
+
+    let entry = Entry {
+        record: Record::Server(encrypted_data),
+        kind,
+        expires_at,
+        views_left,
+        attempts,
+        management_hash: None,
+    };
+    let mut resp = insert(store, id, entry).await?;
+    resp.shares = Some(shares.iter().map(|s| s.encode().into()).collect());
+    Ok(resp)
+}
+
+/// share_decrypt_handler combines the supplied Shamir shares into the
+/// key of a split record, then decrypts and deletes it like
+/// decrypt_handler.
+///
+/// # Errors
+/// A share may not be valid, there may be fewer than the threshold,
+/// and otherwise the same failures as decrypt_handler.
+pub async fn share_decrypt_handler(
+    store: &dyn SecretStore,
+    crypto_config: &CryptoConfig,
+    id: String,
+    shares: Vec<SecretString>,
+) -> Result<DecryptResponse, CipherLinkError> {
+    let shares = shares
+        .iter()
+        .map(|s| Share::parse(s.expose()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(CipherLinkError::BadRequest)?;
+    let (record_id, keyring) = (id.clone(), crypto_config.keyring.clone());
+    decrypt_and_consume(store, &id, move |data| {
+        decrypt_with_shares(data, &shares, &record_id, &keyring)
+    })
+    .await
+}
+
+/// Inserts a new entry with a fresh management token, returning the
+/// response for its creator.
+async fn insert(
+    store: &dyn SecretStore,
+    id: String,
+    mut entry: Entry,
+) -> Result<EncryptResponse, CipherLinkError> {
+    let (management_token, management_hash) = token::generate();
+    entry.management_hash = Some(management_hash);
+    let expires_at = entry.expires_at;
+    store.put(&id, entry).await?;
+
+    Ok(EncryptResponse {
+        id,
+        shares: None,
+        expires_at,
+        management_token: management_token.into(),
+    })
+}
+
+/// Fetches a record, decrypts it with `open` and only then uses up a
+/// view, so a wrong key leaves the link usable. Consuming is atomic:
+/// of several requests that decrypt a record with N views left
+/// concurrently, only N get the plaintext.
+async fn decrypt_and_consume(
+    store: &dyn SecretStore,
+    id: &str,
+    open: impl FnOnce(&EncryptData) -> Result<Zeroizing<Vec<u8>>, CryptoError> + Send + 'static,
+) -> Result<DecryptResponse, CipherLinkError> {
+    let entry = get_live(store, id).await?;
+    if entry.kind == SecretKind::File {
+        return Err(CipherLinkError::BadRequest(format!(
+            "Item is a file, download it from /files/{}",
+            id
+        )));
+    }
+    let plain_text = open_record(store, id, &entry.record, open).await?;
+
+    let views_left = store.consume(id).await?;
+
+    Ok(DecryptResponse {
+        plain_text: plain_text.into(),
+        kind: entry.kind,
+        views_left,
+    })
+}
+
+/// Decrypts a server-side record with `open` off the runtime, counting
+/// a wrong key as a failed attempt. The plain text is checked before
+/// anything is consumed, so a record that can't be shown keeps its
+/// view.
+///
+/// # Errors
+/// Errors if the record was encrypted client-side, `open` fails or the
+/// plain text is not UTF-8.
+async fn open_record(
+    store: &dyn SecretStore,
+    id: &str,
+    record: &Record,
+    open: impl FnOnce(&EncryptData) -> Result<Zeroizing<Vec<u8>>, CryptoError> + Send + 'static,
+) -> Result<String, CipherLinkError> {
+    let data = match record {
+        Record::Server(data) => data.clone(),
+        Record::Client(_) => {
+            return Err(CipherLinkError::BadRequest(
+                "Item was encrypted client-side".into(),
+            ));
+        }
+    };
+
+    let decrypted_data = match run_blocking(move || open(&data)).await? {
+        Ok(decrypted_data) => decrypted_data,
+        Err(e) if e.is_wrong_key() => return Err(fail_attempt(store, id, e).await),
+        Err(e) => return Err(e.into()),
+    };
+    std::str::from_utf8(&decrypted_data)
+        .map(str::to_string)
+        .map_err(|_| CipherLinkError::Corrupt("Plain text is not UTF-8".into()))
+}
+
+/// Runs `f`, e.g. an Argon2id derivation, on the blocking thread pool
+/// so it doesn't stall the runtime, at most `MAX_CONCURRENT_KDF` at
+/// once.
+///
+/// # Errors
+/// Errors if `f` panics.
+async fn run_blocking<T, F>(f: F) -> Result<T, CipherLinkError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let permit = KDF_PERMITS
+        .clone()
+        .acquire_owned()
         .await
-        .map_err(|e| format!("DB get failed: {}", e))?;
+        .map_err(|e| CipherLinkError::Unavailable(e.to_string()))?;
+    // The permit goes with `f`, a dropped request doesn't free it
+    // while the derivation still runs.
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        f()
+    })
+    .await
+    .map_err(|e| CipherLinkError::Unavailable(e.to_string()))
+}
 
-    let transformed_data =
-        item_to_encryt_data(&data).map_err(|e| format!("Transform failed: {}", e))?;
+/// Counts a wrong key against the record's attempt limit, returning
+/// the error to report.
+async fn fail_attempt(store: &dyn SecretStore, id: &str, err: CryptoError) -> CipherLinkError {
+    match store
+        .fail_attempt(id, store::now().saturating_add(LOCKOUT_SECONDS))
+        .await
+    {
+        Ok(AttemptOutcome::Locked) => CipherLinkError::Locked,
+        Ok(AttemptOutcome::Destroyed) => CipherLinkError::Destroyed,
+        // Still a wrong key if the record is gone or the count failed.
+        Ok(AttemptOutcome::Counted) | Err(_) => CipherLinkError::WrongKey(err),
+    }
+}
 
-    let decrypted_data =
-        decrypt(&transformed_data, &key).map_err(|e| format!("Decrypt failed: {}", e))?;
+/// Returns the entry under `id` unless it expired or is locked.
+/// DynamoDB and the local stores keep expired records until they are
+/// swept, they are treated as gone here.
+///
+/// # Errors
+/// Errors with `Expired` if it expired, `Locked` if too many wrong keys
+/// locked it, or if the store get fails.
+async fn get_live(store: &dyn SecretStore, id: &str) -> Result<Entry, CipherLinkError> {
+    let entry = store.get(id).await?;
+    let now = store::now();
+    if entry.is_expired(now) {
+        return Err(CipherLinkError::Expired);
+    }
+    if entry.attempts.is_locked(now) {
+        return Err(CipherLinkError::Locked);
+    }
+    Ok(entry)
+}
 
-    db_client
-        .delete("encryptData", "id", &id)
+/// Checks the plain text is a valid secret of the requested kind,
+/// defaulting to a redirect.
+///
+/// # Errors
+/// Errors if the plain text doesn't fit the kind, e.g. a redirect that
+/// is not a URL, or the kind is `file`, which is only uploaded to
+/// /files.
+fn resolve_kind(kind: Option<SecretKind>, plain_text: &str) -> Result<SecretKind, CipherLinkError> {
+    let kind = kind.unwrap_or_default();
+    let valid = match kind {
+        SecretKind::Redirect => url::Url::parse(plain_text).is_ok(),
+        SecretKind::Text => !plain_text.is_empty(),
+        SecretKind::Json => serde_json::from_str::<serde::de::IgnoredAny>(plain_text).is_ok(),
+        SecretKind::File => {
+            return Err(CipherLinkError::BadRequest(
+                "Files are uploaded to /files".into(),
+            ));
+        }
+    };
+    if !valid {
+        return Err(CipherLinkError::BadRequest(format!(
+            "plain_text is not a valid {} secret",
+            kind.name()
+        )));
+    }
+    Ok(kind)
+}
+
+/// Checks the requested number of views, defaulting to one-time.
+///
+/// # Errors
+/// Errors if it is not between 1 and MAX_VIEWS.
+fn resolve_max_views(max_views: Option<u32>) -> Result<u32, CipherLinkError> {
+    match max_views.unwrap_or(1) {
+        views @ 1..=MAX_VIEWS => Ok(views),
+        _ => Err(CipherLinkError::BadRequest(format!(
+            "max_views must be between 1 and {}",
+            MAX_VIEWS
+        ))),
+    }
+}
+
+/// Checks the requested attempt limit, defaulting to locking after
+/// DEFAULT_MAX_ATTEMPTS.
+///
+/// # Errors
+/// Errors if max_attempts is not between 1 and MAX_ATTEMPTS.
+fn resolve_attempt_limit(
+    max_attempts: Option<u32>,
+    on_max_attempts: Option<Lockout>,
+) -> Result<Attempts, CipherLinkError> {
+    let max_attempts = match max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS) {
+        attempts @ 1..=MAX_ATTEMPTS => attempts,
+        _ => {
+            return Err(CipherLinkError::BadRequest(format!(
+                "max_attempts must be between 1 and {}",
+                MAX_ATTEMPTS
+            )));
+        }
+    };
+    Ok(Attempts {
+        limit: Some(AttemptLimit {
+            max_attempts,
+            lockout: on_max_attempts.unwrap_or_default(),
+        }),
+        ..Attempts::default()
+    })
+}
+
+/// Resolves the requested expiry to a unix time in seconds.
+///
+/// # Errors
+/// Errors if both `ttl_seconds` and `expires_at` are set, or the
+/// expiry is not in the future.
+fn resolve_expiry(expiry: &Expiry) -> Result<Option<u64>, CipherLinkError> {
+    let now = store::now();
+    let expires_at = match (expiry.ttl_seconds, expiry.expires_at) {
+        (Some(_), Some(_)) => {
+            return Err(CipherLinkError::BadRequest(
+                "Set either ttl_seconds or expires_at, not both".into(),
+            ));
+        }
+        (Some(ttl), None) => Some(now.saturating_add(ttl)),
+        (None, expires_at) => expires_at,
+    };
+    match expires_at {
+        Some(expires_at) if expires_at <= now => Err(CipherLinkError::BadRequest(
+            "Expiry must be in the future".into(),
+        )),
+        expires_at => Ok(expires_at),
+    }
+}
+
+/// client_encrypt_handler stores an envelope the client already
+/// encrypted and returns a UUID. The server never sees the key or the
+/// plaintext; the link to share is /open/{id}#{key}.
+///
+/// # Errors
+/// The ciphertext may not be valid base64 or be too large, and
+/// inserting to the store can fail.
+pub async fn client_encrypt_handler(
+    store: &dyn SecretStore,
+    encrypt_request: ClientEncryptRequest,
+) -> Result<EncryptResponse, CipherLinkError> {
+    let ciphertext = STANDARD
+        .decode(&encrypt_request.ciphertext)
+        .map_err(|_| CipherLinkError::BadRequest("Ciphertext is not valid base64".into()))?;
+    if ciphertext.is_empty() || ciphertext.len() > MAX_CLIENT_CIPHERTEXT_LEN {
+        return Err(CipherLinkError::BadRequest(format!(
+            "Ciphertext must be between 1 and {} bytes",
+            MAX_CLIENT_CIPHERTEXT_LEN
+        )));
+    }
+    let expires_at = resolve_expiry(&encrypt_request.expiry)?;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let entry = Entry {
+        record: Record::Client(ciphertext),
+        // The page that decrypts it only redirects.
+        kind: SecretKind::Redirect,
+        expires_at,
+        views_left: 1,
+        // The server can't tell a wrong key, so nothing is counted.
+        attempts: Attempts::default(),
+        management_hash: None,
+    };
+    insert(store, id, entry).await
+}
+
+/// retrieve_handler returns a client-side envelope without decrypting
+/// it and deletes it. The server can't check the key, so the link is
+/// consumed by the first retrieval whether or not the key is right.
+/// Server-side records are never returned, their ciphertext stays on
+/// the server.
+///
+/// # Errors
+/// Potential failures on retrieving/deleting from the store, or the
+/// record was not encrypted client-side.
+pub async fn retrieve_handler(
+    store: &dyn SecretStore,
+    id: String,
+) -> Result<RetrieveResponse, CipherLinkError> {
+    // Check the kind first, taking a server-side record would destroy it.
+    if let Record::Server(_) = get_live(store, &id).await?.record {
+        return Err(CipherLinkError::BadRequest(
+            "Item was not encrypted client-side".into(),
+        ));
+    }
+    let Record::Client(ciphertext) = store.take(&id).await?.record else {
+        return Err(CipherLinkError::BadRequest(
+            "Item was not encrypted client-side".into(),
+        ));
+    };
+
+    Ok(RetrieveResponse {
+        ciphertext: STANDARD.encode(ciphertext),
+    })
+}
+
+/// status_handler tells a link's owner whether it is still usable and
+/// how many views it has left, without consuming it.
+///
+/// # Errors
+/// The management token may be wrong, or the store get can fail.
+pub async fn status_handler(
+    store: &dyn SecretStore,
+    id: String,
+    management_token: &str,
+) -> Result<LinkStatus, CipherLinkError> {
+    let Some(entry) = get_managed(store, &id, management_token).await? else {
+        return Ok(LinkStatus::gone());
+    };
+    let now = store::now();
+    let status = if entry.is_expired(now) {
+        "expired"
+    } else if entry.attempts.is_locked(now) {
+        "locked"
+    } else {
+        "active"
+    };
+    Ok(LinkStatus {
+        status,
+        views_left: Some(entry.views_left),
+        expires_at: entry.expires_at,
+        locked_until: entry.attempts.locked_until.filter(|_| status == "locked"),
+    })
+}
+
+/// revoke_handler deletes a link before it is opened. Revoking a link
+/// that is already gone succeeds. A file's chunks go with it, unless
+/// there is no blob store here, e.g. in Lambda mode, and the sweep of
+/// the deployment that keeps them deletes them.
+///
+/// # Errors
+/// The management token may be wrong, or the store can fail.
+pub async fn revoke_handler(
+    store: &dyn SecretStore,
+    blobs: Option<&dyn BlobStore>,
+    id: String,
+    management_token: &str,
+) -> Result<LinkStatus, CipherLinkError> {
+    if get_managed(store, &id, management_token).await?.is_some() {
+        match store.take(&id).await {
+            Ok(entry) if entry.kind == SecretKind::File => {
+                if let Some(blobs) = blobs {
+                    blobs.delete(&id).await?;
+                }
+            }
+            Ok(_) => {}
+            // Opened or revoked since the check.
+            Err(CipherLinkError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(LinkStatus::gone())
+}
+
+/// update_expiry_handler moves a link's expiry, extending or
+/// shortening it.
+///
+/// # Errors
+/// The management token may be wrong, the expiry may be missing or not
+/// in the future, the link may be gone or already expired, or the store
+/// can fail.
+pub async fn update_expiry_handler(
+    store: &dyn SecretStore,
+    id: String,
+    management_token: &str,
+    expiry: Expiry,
+) -> Result<LinkStatus, CipherLinkError> {
+    let Some(expires_at) = resolve_expiry(&expiry)? else {
+        return Err(CipherLinkError::BadRequest(
+            "Set ttl_seconds or expires_at".into(),
+        ));
+    };
+    let Some(entry) = get_managed(store, &id, management_token).await? else {
+        return Err(CipherLinkError::not_found(&id));
+    };
+    if entry.is_expired(store::now()) {
+        return Err(CipherLinkError::Expired);
+    }
+    store.set_expiry(&id, expires_at).await?;
+    status_handler(store, id, management_token).await
+}
+
+/// Fetches an entry for its owner, or None if it is gone. A missing
+/// entry is reported without checking the token, there is nothing left
+/// to protect.
+///
+/// # Errors
+/// Errors if the entry has no management token, the token doesn't
+/// match, or the store get fails.
+async fn get_managed(
+    store: &dyn SecretStore,
+    id: &str,
+    management_token: &str,
+) -> Result<Option<Entry>, CipherLinkError> {
+    let entry = match store.get(id).await {
+        Ok(entry) => entry,
+        Err(CipherLinkError::NotFound(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let Some(management_hash) = &entry.management_hash else {
+        return Err(CipherLinkError::Forbidden("Link can't be managed".into()));
+    };
+    if !token::verify(management_token, management_hash) {
+        return Err(CipherLinkError::Forbidden(
+            "Invalid management token".into(),
+        ));
+    }
+    Ok(Some(entry))
+}
+
+/// Reads a decrypt key from an `Authorization: Bearer <key>` header.
+pub fn bearer_key(authorization: &str) -> Option<SecretString> {
+    let (scheme, key) = authorization.split_once(' ')?;
+    let key = key.trim();
+    if !scheme.eq_ignore_ascii_case(KEY_AUTH_SCHEME) || key.is_empty() {
+        return None;
+    }
+    Some(key.to_string().into())
+}
+
+/// Parses a POST /decrypt/{id} body. It is JSON, or a form with a
+/// `key` field when the unlock page submits it.
+///
+/// # Errors
+/// Errors if the body doesn't parse or the form has no key.
+pub fn parse_decrypt_payload(
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<DecryptPayload, CipherLinkError> {
+    if content_type.is_some_and(|ct| ct.starts_with("application/x-www-form-urlencoded")) {
+        return url::form_urlencoded::parse(body)
+            .find(|(name, _)| name == "key")
+            .map(|(_, key)| {
+                DecryptPayload::Key(KeyDecryptRequest {
+                    key: key.into_owned().into(),
+                })
+            })
+            .ok_or_else(|| CipherLinkError::BadRequest("Missing key".into()));
+    }
+    serde_json::from_slice(body)
+        .map_err(|e| CipherLinkError::BadRequest(format!("Invalid body: {}", e)))
+}
+
+/// unlock_page_handler returns the HTML/JS page for /unlock/{id}#{key}
+/// links, which submits the key to POST /decrypt/{id}.
+pub async fn unlock_page_handler() -> &'static str {
+    UNLOCK_PAGE
+}
+
+/// open_page_handler returns the HTML/JS page that fetches a
+/// client-side envelope, decrypts it in the browser and redirects.
+pub async fn open_page_handler() -> &'static str {
+    OPEN_PAGE
+}
+
+/// secret_page renders the page a `text` secret is shown on once.
+pub fn secret_page(secret: &str) -> String {
+    let mut escaped = String::with_capacity(secret.len());
+    for c in secret.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    SECRET_PAGE.replace("{{secret}}", &escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blob::FsBlobStore,
+        crypto::{KdfParams, keyring::Keyring},
+        store::MemoryStore,
+    };
+
+    fn test_config() -> CryptoConfig {
+        CryptoConfig {
+            kdf_params: KdfParams {
+                m_cost: 64,
+                t_cost: 1,
+                p_cost: 1,
+            },
+            ..CryptoConfig::default()
+        }
+    }
+
+    fn encrypt_request(plain_text: &str, key: &str) -> EncryptRequest {
+        EncryptRequest {
+            plain_text: plain_text.to_string().into(),
+            key: key.to_string().into(),
+            kind: None,
+            max_views: None,
+            max_attempts: None,
+            on_max_attempts: None,
+            expiry: Expiry::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_is_one_time() {
+        let store = MemoryStore::new();
+        let config = test_config();
+        let resp = encrypt_handler(
+            &store,
+            &config,
+            encrypt_request("http://yahoo.com", "music"),
+        )
         .await
-        .map_err(|e| format!("Delete failed: {}", e))?;
+        .expect("encrypt failed");
+
+        // A wrong key leaves the link usable.
+        assert!(
+            decrypt_handler(&store, &config, resp.id.clone(), "wrong".to_string().into())
+                .await
+                .is_err()
+        );
+        let got = decrypt_handler(&store, &config, resp.id.clone(), "music".to_string().into())
+            .await
+            .expect("decrypt failed");
+        assert_eq!("http://yahoo.com", got.plain_text.expose());
+        assert_eq!(0, got.views_left);
+        assert!(
+            decrypt_handler(&store, &config, resp.id, "music".to_string().into())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_decrypt_has_one_winner() {
+        let store = std::sync::Arc::new(MemoryStore::new());
+        let config = std::sync::Arc::new(test_config());
+        let resp = encrypt_handler(
+            store.as_ref(),
+            &config,
+            encrypt_request("http://yahoo.com", "music"),
+        )
+        .await
+        .expect("encrypt failed");
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let (store, config, id) = (store.clone(), config.clone(), resp.id.clone());
+                tokio::spawn(async move {
+                    decrypt_handler(store.as_ref(), &config, id, "music".to_string().into())
+                        .await
+                        .is_ok()
+                })
+            })
+            .collect();
+        let mut winners = 0;
+        for task in tasks {
+            if task.await.unwrap() {
+                winners += 1;
+            }
+        }
+        assert_eq!(1, winners);
+    }
+
+    #[tokio::test]
+    async fn test_max_views() {
+        let store = MemoryStore::new();
+        let config = test_config();
+        let mut request = encrypt_request("http://yahoo.com", "music");
+        request.max_views = Some(2);
+        let resp = encrypt_handler(&store, &config, request)
+            .await
+            .expect("encrypt failed");
+
+        for views_left in [1, 0] {
+            let got = decrypt_handler(&store, &config, resp.id.clone(), "music".to_string().into())
+                .await
+                .expect("decrypt failed");
+            assert_eq!(views_left, got.views_left);
+        }
+        assert!(
+            decrypt_handler(&store, &config, resp.id, "music".to_string().into())
+                .await
+                .is_err()
+        );
 
-    Ok(String::from_utf8_lossy(&decrypted_data).to_string())
+        let mut request = encrypt_request("http://yahoo.com", "music");
+        request.max_views = Some(0);
+        assert!(encrypt_handler(&store, &config, request).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_attempt_limit() {
+        let store = MemoryStore::new();
+        let config = test_config();
+        let decrypt = |id: &str, key: &str| {
+            decrypt_handler(&store, &config, id.to_string(), key.to_string().into())
+        };
+
+        let mut request = encrypt_request("http://yahoo.com", "music");
+        request.max_attempts = Some(2);
+        let locked = encrypt_handler(&store, &config, request)
+            .await
+            .expect("encrypt failed");
+        assert!(decrypt(&locked.id, "wrong").await.is_err());
+        assert_eq!(
+            CipherLinkError::Locked,
+            decrypt(&locked.id, "wrong").await.unwrap_err()
+        );
+        // Even the right key is refused until the cooldown passes.
+        assert_eq!(
+            CipherLinkError::Locked,
+            decrypt(&locked.id, "music").await.unwrap_err()
+        );
+        assert!(store.get(&locked.id).await.is_ok());
+
+        let mut request = encrypt_request("http://yahoo.com", "music");
+        request.max_attempts = Some(1);
+        request.on_max_attempts = Some(Lockout::Destroy);
+        let destroyed = encrypt_handler(&store, &config, request)
+            .await
+            .expect("encrypt failed");
+        assert!(decrypt(&destroyed.id, "wrong").await.is_err());
+        assert!(store.get(&destroyed.id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tampered_wrapped_key_is_not_a_wrong_key() {
+        let store = MemoryStore::new();
+        let keyring = Keyring::from_spec("a:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=", "a");
+        let config = CryptoConfig {
+            keyring: keyring.unwrap(),
+            ..test_config()
+        };
+        let mut request = encrypt_request("http://yahoo.com", "music");
+        request.max_attempts = Some(1);
+        request.on_max_attempts = Some(Lockout::Destroy);
+        let resp = encrypt_handler(&store, &config, request)
+            .await
+            .expect("encrypt failed");
+
+        let Record::Server(current) = store.get(&resp.id).await.unwrap().record else {
+            panic!("expected a server record");
+        };
+        let mut tampered = current.clone();
+        let wrapped = tampered.wrapped_key.as_mut().expect("no wrapped key");
+        *wrapped.wrapped.last_mut().unwrap() ^= 1;
+        store
+            .swap_envelope(&resp.id, &current, &tampered)
+            .await
+            .unwrap();
+
+        let err = decrypt_handler(&store, &config, resp.id.clone(), "music".to_string().into())
+            .await
+            .unwrap_err();
+        assert_eq!(CipherLinkError::Crypto(CryptoError::MasterKeyUnwrap), err);
+        assert_eq!(500, err.status());
+        // Not counted, or the single attempt would have destroyed it.
+        let entry = store.get(&resp.id).await.expect("record was destroyed");
+        assert_eq!(0, entry.attempts.failed);
+    }
+
+    #[tokio::test]
+    async fn test_expired_link_is_gone() {
+        let store = MemoryStore::new();
+        let config = test_config();
+        let mut request = encrypt_request("http://yahoo.com", "music");
+        request.expiry.ttl_seconds = Some(60);
+        let resp = encrypt_handler(&store, &config, request)
+            .await
+            .expect("encrypt failed");
+        assert!(resp.expires_at.is_some());
+
+        // Expire it without waiting, as if the sweeper had not run yet.
+        store.set_expiry(&resp.id, store::now() - 1).await.unwrap();
+        let err = decrypt_handler(&store, &config, resp.id, "music".to_string().into())
+            .await
+            .expect_err("expired link decrypted");
+        assert_eq!(CipherLinkError::Expired, err);
+    }
+
+    #[tokio::test]
+    async fn test_manage_link() {
+        let store = MemoryStore::new();
+        // Never written to, the link is not a file.
+        let blobs = FsBlobStore::new(std::env::temp_dir().join("cipherlink-unused"));
+        let config = test_config();
+        let mut request = encrypt_request("http://yahoo.com", "music");
+        request.max_views = Some(2);
+        let resp = encrypt_handler(&store, &config, request)
+            .await
+            .expect("encrypt failed");
+        let token = resp.management_token.expose();
+
+        let err = status_handler(&store, resp.id.clone(), "wrong")
+            .await
+            .expect_err("wrong token accepted");
+        assert_eq!(403, err.status());
+        let status = status_handler(&store, resp.id.clone(), token)
+            .await
+            .expect("status failed");
+        assert_eq!(
+            ("active", Some(2), None),
+            (status.status, status.views_left, status.expires_at)
+        );
+
+        let expiry = Expiry {
+            ttl_seconds: Some(60),
+            expires_at: None,
+        };
+        let status = update_expiry_handler(&store, resp.id.clone(), token, expiry)
+            .await
+            .expect("update failed");
+        assert!(status.expires_at.is_some_and(|at| at > store::now()));
+
+        assert!(
+            revoke_handler(&store, Some(&blobs), resp.id.clone(), "wrong")
+                .await
+                .is_err()
+        );
+        let status = revoke_handler(&store, Some(&blobs), resp.id.clone(), token)
+            .await
+            .expect("revoke failed");
+        assert_eq!("gone", status.status);
+        assert!(
+            decrypt_handler(&store, &config, resp.id.clone(), "music".to_string().into())
+                .await
+                .is_err()
+        );
+        let status = status_handler(&store, resp.id, "anything")
+            .await
+            .expect("status failed");
+        assert_eq!("gone", status.status);
+    }
+
+    #[test]
+    fn test_decrypt_key_sources() {
+        let key = bearer_key("Bearer music").expect("no key");
+        assert_eq!("music", key.expose());
+        assert!(bearer_key("Basic bXVzaWM=").is_none());
+        assert!(bearer_key("Bearer ").is_none());
+
+        let form =
+            parse_decrypt_payload(Some("application/x-www-form-urlencoded"), b"key=a%20b%26c");
+        let Ok(DecryptPayload::Key(req)) = form else {
+            panic!("expected a key");
+        };
+        assert_eq!("a b&c", req.key.expose());
+        assert!(parse_decrypt_payload(Some("application/x-www-form-urlencoded"), b"").is_err());
+
+        let json = parse_decrypt_payload(Some("application/json"), br#"{"key":"music"}"#);
+        assert!(matches!(json, Ok(DecryptPayload::Key(_))));
+        let json = parse_decrypt_payload(None, br#"{"identity":"AA=="}"#);
+        assert!(matches!(json, Ok(DecryptPayload::Recipient(_))));
+    }
+
+    #[test]
+    fn test_resolve_expiry() {
+        assert_eq!(Ok(None), resolve_expiry(&Expiry::default()));
+        let both = Expiry {
+            ttl_seconds: Some(60),
+            expires_at: Some(store::now() + 60),
+        };
+        assert!(resolve_expiry(&both).is_err());
+        let past = Expiry {
+            ttl_seconds: None,
+            expires_at: Some(1),
+        };
+        assert!(resolve_expiry(&past).is_err());
+        let zero = Expiry {
+            ttl_seconds: Some(0),
+            expires_at: None,
+        };
+        assert!(resolve_expiry(&zero).is_err());
+    }
+
+    #[test]
+    fn test_resolve_kind() {
+        assert_eq!(
+            Ok(SecretKind::Redirect),
+            resolve_kind(None, "http://yahoo.com")
+        );
+        assert!(resolve_kind(None, "hunter2").is_err());
+        assert_eq!(
+            Ok(SecretKind::Text),
+            resolve_kind(Some(SecretKind::Text), "hunter2")
+        );
+        assert!(resolve_kind(Some(SecretKind::Json), "{").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_only_client_records() {
+        let store = MemoryStore::new();
+        let resp = client_encrypt_handler(
+            &store,
+            ClientEncryptRequest {
+                ciphertext: STANDARD.encode(b"envelope"),
+                expiry: Expiry::default(),
+            },
+        )
+        .await
+        .expect("encrypt failed");
+        assert!(
+            decrypt_handler(
+                &store,
+                &test_config(),
+                resp.id.clone(),
+                "key".to_string().into()
+            )
+            .await
+            .is_err()
+        );
+        let got = retrieve_handler(&store, resp.id.clone())
+            .await
+            .expect("retrieve failed");
+        assert_eq!(STANDARD.encode(b"envelope"), got.ciphertext);
+        assert!(retrieve_handler(&store, resp.id).await.is_err());
+    }
 }
@@ -1,23 +1,14 @@
 use crate::app_config::AppConfig;
-use crate::db;
-use crate::lambda::routing::router;
-use lambda_http::{Request, run, service_fn};
+use crate::rest::router;
+use crate::store;
 
-mod helpers;
-mod routing;
-
-use std::sync::Arc;
-
-/// Start lambda rust runtime.
+/// Start lambda rust runtime. lambda_http turns each event into an
+/// HTTP request for the same router server mode serves. /files is not
+/// served: chunks are kept on local disk, which Lambda instances
+/// neither share nor keep.
 pub async fn init(config: AppConfig) {
-    // arc allows db_client to be cloned and shared across requests.
-    let db_client = Arc::new(db::init(&config.db_url, &config.region).await);
-
-    // move allows the below closure to own db_client.
-    let handler = service_fn(move |event: Request| {
-        let db = db_client.clone(); // Each request get it's own db_client reference.
-        async move { router(event, &db).await }
-    });
+    let store = store::init(&config).await;
+    let app = router(store, None, config.crypto, config.legacy_key_path);
 
-    run(handler).await.expect("Lambda runtime failed");
+    lambda_http::run(app).await.expect("Lambda runtime failed");
 }
@@ -1,13 +1,25 @@
 use std::env;
 
-use crate::{app_config::AppConfig, crypto::encrypt, transformer::encrypt_data_to_item};
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use zeroize::Zeroizing;
+
+use crate::{
+    app_config::AppConfig,
+    crypto::{encrypt, rewrap},
+    handlers::{UPLOAD_GRACE_SECS, sweep_files},
+    store::{Entry, Record},
+};
 
 mod app_config;
+mod blob;
 mod crypto;
 mod db;
+mod error;
 mod handlers;
 mod lambda;
 mod rest;
+mod store;
 mod transformer;
 mod types;
 
@@ -28,6 +40,9 @@ async fn main() {
         "server" => rest::init(config).await,
         "seed" => seed_db(config).await,
         "lambda" => lambda::init(config).await,
+        "rotate-keys" => rotate_keys(config).await,
+        "sweep-files" => sweep_expired_files(config).await,
+        "keygen" => keygen(),
         _ => {
             eprintln!("Unknown mode: '{}'.", mode);
             std::process::exit(1);
@@ -36,39 +51,112 @@ async fn main() {
 }
 
 async fn seed_db(config: AppConfig) {
-    println!("Starting 'db' mode, seeding DynamoDB....");
+    println!("Starting 'db' mode, seeding the store....");
 
-    let db_client = db::init(&config.db_url, &config.region).await;
-    let table_name = "encryptData";
-    let attribute_name = "id";
-    db_client
-        .init_table(table_name, attribute_name)
-        .await
-        .expect("unable to initialize db");
+    let store = store::init(&config).await;
+    store.setup().await.expect("unable to initialize db");
 
+    let id1 = "id1";
     let plain_text1 = "google.com";
     let key1: &'static str = "key1";
-    let encrypt_data1 = encrypt(plain_text1, key1).unwrap();
+    let encrypt_data1 = encrypt(plain_text1, key1, id1, &config.crypto).unwrap();
 
+    let id2 = "id2";
     let plain_text2 = "amazon.co.jp";
     let key2 = "key2";
-    let encrypt_data2 = encrypt(plain_text2, key2).unwrap();
+    let encrypt_data2 = encrypt(plain_text2, key2, id2, &config.crypto).unwrap();
 
-    let id1 = "id1";
-    db_client
-        .insert(table_name, encrypt_data_to_item(id1, &encrypt_data1))
+    store
+        .put(id1, Entry::new(Record::Server(encrypt_data1)))
         .await
         .unwrap();
 
-    let id2 = "id2";
-    db_client
-        .insert(table_name, encrypt_data_to_item(id2, &encrypt_data2))
+    store
+        .put(id2, Entry::new(Record::Server(encrypt_data2)))
         .await
         .unwrap();
 
     println!("items inserted");
-    // db_client
-    //     .dump_table(table_name)
-    //     .await
-    //     .expect("unable to dump table")
+}
+
+/// Re-wraps every record's data key under the active master key so
+/// older master keys can be retired. User keys are not needed since
+/// only the wrapped data key changes.
+async fn rotate_keys(config: AppConfig) {
+    let Some(active) = config.crypto.keyring.active_id() else {
+        eprintln!("CONFIG_MASTER_KEYS must be set to rotate keys.");
+        std::process::exit(1);
+    };
+    println!(
+        "Starting 'rotate-keys' mode, re-wrapping under '{}'....",
+        active
+    );
+
+    let store = store::init(&config).await;
+    let ids = store.list().await.expect("unable to list records");
+
+    let mut rotated = 0;
+    for id in ids {
+        let entry = match store.get(&id).await {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("skipping '{}': {}", id, e);
+                continue;
+            }
+        };
+        let Record::Server(current) = entry.record else {
+            continue;
+        };
+        let mut data = current.clone();
+        match rewrap(&mut data, &id, &config.crypto.keyring) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                eprintln!("skipping '{}': {}", id, e);
+                continue;
+            }
+        }
+        // Only the envelope is swapped, so views and failed attempts
+        // counted since the read are kept. It fails if the record was
+        // consumed or changed meanwhile.
+        match store.swap_envelope(&id, &current, &data).await {
+            Ok(()) => rotated += 1,
+            Err(e) => eprintln!("skipping '{}': {}", id, e),
+        }
+    }
+
+    println!("{} items re-wrapped", rotated);
+}
+
+/// Deletes the chunks of files whose record is gone or expired. Server
+/// mode does this on its own every few minutes, this is for running it
+/// from a scheduler instead.
+async fn sweep_expired_files(config: AppConfig) {
+    println!("Starting 'sweep-files' mode....");
+
+    let store = store::init(&config).await;
+    let blobs = blob::init(&config);
+    let before = store::now().saturating_sub(UPLOAD_GRACE_SECS);
+    match sweep_files(store.as_ref(), blobs.as_ref(), before).await {
+        Ok(swept) => println!("{} files swept", swept),
+        Err(e) => {
+            eprintln!("sweep failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints a new X25519 key pair for encrypting links to a recipient,
+/// and a new master key. The public key goes in /encrypt's
+/// "recipients", the private key stays with the recipient. The master
+/// key goes in CONFIG_MASTER_KEYS as `<id>:<master key>`.
+fn keygen() {
+    let identity = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+    let public = x25519_dalek::PublicKey::from(&identity);
+    println!("public key:  {}", STANDARD.encode(public.as_bytes()));
+    println!("private key: {}", STANDARD.encode(identity.as_bytes()));
+
+    let mut master_key = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(master_key.as_mut());
+    println!("master key:  {}", STANDARD.encode(master_key.as_ref()));
 }
@@ -1,76 +1,877 @@
+use std::{sync::Arc, time::Duration};
+
 use axum::{
     Extension, Json, Router,
-    extract::Path,
-    response::{IntoResponse, Redirect, Response},
+    body::{Body, Bytes},
+    extract::{
+        DefaultBodyLimit, Multipart, Path,
+        multipart::{Field, MultipartError, MultipartRejection},
+        rejection::JsonRejection,
+    },
+    http::{HeaderMap, StatusCode, header},
+    response::{Html, IntoResponse, Redirect, Response},
     routing::{get, post},
 };
 
 use crate::{
     app_config::AppConfig,
-    db::{self, DynamoDBClient},
-    handlers::{decrypt_handler, encrypt_handler, health_handler},
-    types::{DecryptParams, EncryptApiResponse, EncryptRequest},
+    blob::{self, BlobStore},
+    crypto::CryptoConfig,
+    error::CipherLinkError,
+    handlers::{
+        FileUpload, MAX_FILE_LEN, UPLOAD_GRACE_SECS, bearer_key, client_encrypt_handler,
+        decrypt_handler, encrypt_handler, file_download_handler, health_handler, open_page_handler,
+        parse_decrypt_payload, recipient_decrypt_handler, recipient_encrypt_handler,
+        retrieve_handler, revoke_handler, secret_page, share_decrypt_handler,
+        split_encrypt_handler, status_handler, sweep_files, unlock_page_handler,
+        update_expiry_handler,
+    },
+    store::{self, Lockout, SecretKind, SecretStore},
+    types::{
+        DecryptParams, DecryptPayload, DecryptResponse, EncryptApiResponse, EncryptPayload,
+        EncryptResponse, Expiry, FileUploadRequest, MANAGEMENT_TOKEN_HEADER, SecretResponse,
+        SecretString, VIEWS_LEFT_HEADER,
+    },
 };
 
+/// Upper bound for a /files body: the largest file plus room for the
+/// other fields and the multipart framing.
+const MAX_UPLOAD_BODY_LEN: usize = MAX_FILE_LEN as usize + 64 * 1024;
+
+/// How often server mode sweeps the chunks of expired files.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
 ///  Initialize the app. Creates and runs an axum server and a
-/// dynamodb client based on the input config.
+/// store based on the input config.
 pub async fn init(config: AppConfig) {
-    let db_client = db::init(&config.db_url, &config.region).await;
+    let store = store::init(&config).await;
+    let blobs = blob::init(&config);
+    let addr = format!("0.0.0.0:{}", config.server_port);
+    tokio::spawn(sweep_files_periodically(store.clone(), blobs.clone()));
+    let app = router(store, Some(blobs), config.crypto, config.legacy_key_path);
+
+    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+/// Sweeps the chunks of expired files every `SWEEP_INTERVAL`, one
+/// sweep at a time.
+async fn sweep_files_periodically(store: Arc<dyn SecretStore>, blobs: Arc<dyn BlobStore>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let before = store::now().saturating_sub(UPLOAD_GRACE_SECS);
+        if let Err(e) = sweep_files(store.as_ref(), blobs.as_ref(), before).await {
+            eprintln!("file sweep failed: {}", e);
+        }
+    }
+}
 
-    let app = Router::new()
+/// Builds the app's routes. Server and Lambda mode both serve this
+/// router, so every endpoint and error format exists once.
+/// /decrypt/{id}/{key} is only routed when `legacy_key_path` is set,
+/// /files only when there is a blob store to keep the chunks in.
+pub fn router(
+    store: Arc<dyn SecretStore>,
+    blobs: Option<Arc<dyn BlobStore>>,
+    crypto_config: CryptoConfig,
+    legacy_key_path: bool,
+) -> Router {
+    let mut app = Router::new()
         .route("/health", get(rest_health_handler))
         .route("/encrypt", post(rest_encrypt_handler))
-        .route("/decrypt/{id}/{key}", get(rest_decrypt_handler))
-        .layer(Extension(db_client));
+        .route(
+            "/decrypt/{id}",
+            get(rest_decrypt_header_handler).post(rest_decrypt_payload_handler),
+        )
+        .route("/unlock/{id}", get(rest_unlock_handler))
+        .route("/retrieve/{id}", get(rest_retrieve_handler))
+        .route("/open/{id}", get(rest_open_handler))
+        .route(
+            "/manage/{id}",
+            get(rest_status_handler)
+                .delete(rest_revoke_handler)
+                .patch(rest_update_expiry_handler),
+        );
+    if legacy_key_path {
+        app = app.route("/decrypt/{id}/{key}", get(rest_decrypt_handler));
+    }
+    if let Some(blobs) = blobs {
+        app = app
+            .route(
+                "/files",
+                post(rest_file_upload_handler).layer(DefaultBodyLimit::max(MAX_UPLOAD_BODY_LEN)),
+            )
+            .route(
+                "/files/{id}",
+                get(rest_file_download_handler).post(rest_file_unlock_handler),
+            )
+            .route("/unlock/files/{id}", get(rest_unlock_handler))
+            .layer(Extension(blobs));
+    }
+    app.fallback(rest_not_found_handler)
+        .layer(Extension(store))
+        .layer(Extension(Arc::new(crypto_config)))
+}
 
-    let addr = format!("0.0.0.0:{}", config.server_port);
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+/// not_found_handler answers routes that don't exist in the same
+/// format as every other error.
+async fn rest_not_found_handler() -> Response {
+    CipherLinkError::NotFound("Not Found".into()).into_response()
 }
 
 /// health_handler is just used to see if one can get a response
-/// from the app.
-async fn rest_health_handler() -> Response {
-    let status = health_handler().await;
-    Json(status).into_response()
+/// from the app. Answers 503 if the store can't be reached.
+async fn rest_health_handler(Extension(store): Extension<Arc<dyn SecretStore>>) -> Response {
+    let status = health_handler(store.as_ref()).await;
+    let code = match status.status {
+        "healthy" => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (code, Json(status)).into_response()
 }
 
 /// encrypt_handler for the /encrypt endpoint.
 /// expects a POST and json body like:
 /// {"plain_text":"http://yahoo.com","key":"foobar"}
+/// optionally with "max_views":3 to allow more than one view,
+/// or, for client-side encryption:
+/// {"ciphertext":"<base64 envelope>"}
+/// or, for X25519 recipients:
+/// {"plain_text":"http://yahoo.com","recipients":["<base64 public key>"]}
+/// or, split into shares of which any 2 decrypt:
+/// {"plain_text":"http://yahoo.com","threshold":2,"shares":3}
 /// Returns a UUID that needeed for decryption.
 ///
 /// # Errors
 /// Encryption and inserting to the db can fail.
 pub async fn rest_encrypt_handler(
-    Extension(db_client): Extension<DynamoDBClient>,
-    Json(payload): Json<EncryptRequest>,
+    Extension(store): Extension<Arc<dyn SecretStore>>,
+    Extension(crypto_config): Extension<Arc<CryptoConfig>>,
+    payload: Result<Json<EncryptPayload>, JsonRejection>,
 ) -> Response {
-    match encrypt_handler(&db_client, payload).await {
+    let Json(payload) = match payload {
+        Ok(payload) => payload,
+        Err(rejection) => return invalid_json(rejection),
+    };
+    let result = match payload {
+        EncryptPayload::Server(req) => encrypt_handler(store.as_ref(), &crypto_config, req).await,
+        EncryptPayload::Recipients(req) => {
+            recipient_encrypt_handler(store.as_ref(), &crypto_config, req).await
+        }
+        EncryptPayload::Split(req) => {
+            split_encrypt_handler(store.as_ref(), &crypto_config, req).await
+        }
+        EncryptPayload::Client(req) => client_encrypt_handler(store.as_ref(), req).await,
+    };
+    match result {
         Ok(resp) => Json(EncryptApiResponse::Ok(resp)).into_response(),
-        Err(err) => Json(EncryptApiResponse::Err(err)).into_response(),
+        Err(err) => err.into_response(),
     }
 }
 
-/// decrypt_handler is used for the /decrypt/{id}/{key} endpoint.
+/// decrypt_handler is used for the legacy /decrypt/{id}/{key}
+/// endpoint, served unless CONFIG_LEGACY_KEY_PATH is false.
 /// Requires the key used for the original decryption and UUID
 /// that was returned when the encrypt handle was called.
 /// Assuming a valid UUID and key, the app will redirect the user
-/// to the encrypted URL. The database entry is then deleted.
+/// to the encrypted URL, or show a text or JSON secret, with the
+/// views left in the x-views-left header. The database entry is deleted after its last view.
 ///
 /// # Errors
 /// Potential failures on the following steps retrieving/deleting
 /// from the db, decoding/transforming the data from the db,
 /// and decryption.
 async fn rest_decrypt_handler(
-    Extension(db_client): Extension<DynamoDBClient>,
+    Extension(store): Extension<Arc<dyn SecretStore>>,
+    Extension(crypto_config): Extension<Arc<CryptoConfig>>,
     Path(params): Path<DecryptParams>,
 ) -> Response {
-    match decrypt_handler(&db_client, params.id, params.key).await {
-        Ok(url) => match url::Url::parse(&url) {
-            Ok(valid_url) => Redirect::temporary(valid_url.as_str()).into_response(),
-            Err(_) => "Decrypted data is not a valid URL".into_response(),
+    match decrypt_handler(store.as_ref(), &crypto_config, params.id, params.key).await {
+        Ok(resp) => decrypted_response(resp),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// decrypt_header_handler is used for GET /decrypt/{id} with the key
+/// in an `Authorization: Bearer <key>` header. Answers like
+/// /decrypt/{id}/{key}.
+///
+/// # Errors
+/// Same as rest_decrypt_handler, or the header is missing.
+async fn rest_decrypt_header_handler(
+    Extension(store): Extension<Arc<dyn SecretStore>>,
+    Extension(crypto_config): Extension<Arc<CryptoConfig>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(key) = authorization_key(&headers) else {
+        return CipherLinkError::BadRequest("Missing key".into()).into_response();
+    };
+    match decrypt_handler(store.as_ref(), &crypto_config, id, key).await {
+        Ok(resp) => decrypted_response(resp),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// decrypt_payload_handler is used for POST /decrypt/{id}. Expects a
+/// json body like:
+/// {"key":"foobar"} for records encrypted with a key,
+/// {"identity":"<base64 private key>"} for recipients, or
+/// {"shares":["<base64 share>", ...]} for split records.
+/// The unlock page posts the key as a form instead.
+/// Answers like /decrypt/{id}/{key}.
+///
+/// # Errors
+/// Same as rest_decrypt_handler, or the body is invalid, or the
+/// identity or shares don't unlock the record.
+async fn rest_decrypt_payload_handler(
+    Extension(store): Extension<Arc<dyn SecretStore>>,
+    Extension(crypto_config): Extension<Arc<CryptoConfig>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let payload = match parse_decrypt_payload(content_type, &body) {
+        Ok(payload) => payload,
+        Err(err) => return err.into_response(),
+    };
+    let result = match payload {
+        DecryptPayload::Key(req) => {
+            decrypt_handler(store.as_ref(), &crypto_config, id, req.key).await
+        }
+        DecryptPayload::Recipient(req) => {
+            recipient_decrypt_handler(store.as_ref(), &crypto_config, id, req.identity).await
+        }
+        DecryptPayload::Shares(req) => {
+            share_decrypt_handler(store.as_ref(), &crypto_config, id, req.shares).await
+        }
+    };
+    match result {
+        Ok(resp) => decrypted_response(resp),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Hands out a decrypted secret by its kind, telling how many more
+/// times the link can be opened. URLs are redirected to with a 303 so a
+/// POSTed key is not sent on. Text and JSON are answered directly and
+/// must not be cached.
+fn decrypted_response(resp: DecryptResponse) -> Response {
+    let views_left = (VIEWS_LEFT_HEADER, resp.views_left.to_string());
+    let no_store = (header::CACHE_CONTROL.as_str(), "no-store".to_string());
+    match resp.kind {
+        SecretKind::Redirect => match url::Url::parse(resp.plain_text.expose()) {
+            Ok(valid_url) => ([views_left], Redirect::to(valid_url.as_str())).into_response(),
+            Err(_) => CipherLinkError::InvalidUrl.into_response(),
+        },
+        SecretKind::Text => (
+            [views_left, no_store],
+            Html(secret_page(resp.plain_text.expose())),
+        )
+            .into_response(),
+        SecretKind::Json => match serde_json::from_str(resp.plain_text.expose()) {
+            Ok(secret) => (
+                [views_left, no_store],
+                Json(SecretResponse {
+                    secret,
+                    views_left: resp.views_left,
+                }),
+            )
+                .into_response(),
+            Err(_) => CipherLinkError::Corrupt("Secret is not valid JSON".into()).into_response(),
         },
-        Err(err) => Json(err).into_response(),
+        // Only handed out by /files/{id}.
+        SecretKind::File => CipherLinkError::BadRequest("Item is a file".into()).into_response(),
+    }
+}
+
+/// retrieve_handler is used for the /retrieve/{id} endpoint.
+/// Returns the client-side envelope once, then deletes it.
+///
+/// # Errors
+/// Retrieving/deleting from the db can fail, or the record was
+/// encrypted server-side.
+async fn rest_retrieve_handler(
+    Extension(store): Extension<Arc<dyn SecretStore>>,
+    Path(id): Path<String>,
+) -> Response {
+    match retrieve_handler(store.as_ref(), id).await {
+        Ok(resp) => Json(resp).into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// unlock_handler is used for the /unlock/{id} and /unlock/files/{id}
+/// endpoints. Serves the page that posts the key in the fragment to
+/// POST /decrypt/{id}, or POST /files/{id} for a file.
+async fn rest_unlock_handler() -> Response {
+    Html(unlock_page_handler().await).into_response()
+}
+
+/// open_handler is used for the /open/{id} endpoint. Serves the page
+/// that decrypts a client-side envelope with the key in the fragment.
+async fn rest_open_handler() -> Response {
+    Html(open_page_handler().await).into_response()
+}
+
+/// status_handler is used for GET /manage/{id}. Requires the link's
+/// management token in the x-management-token header and returns its
+/// status, e.g. {"status":"active","views_left":1}.
+///
+/// # Errors
+/// The token may be missing or wrong, or the store get can fail.
+async fn rest_status_handler(
+    Extension(store): Extension<Arc<dyn SecretStore>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(management_token) = management_token(&headers) else {
+        return missing_management_token();
+    };
+    match status_handler(store.as_ref(), id, management_token).await {
+        Ok(resp) => Json(resp).into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// revoke_handler is used for DELETE /manage/{id}. Deletes the link
+/// so it can't be opened anymore.
+///
+/// # Errors
+/// The token may be missing or wrong, or the store can fail.
+async fn rest_revoke_handler(
+    Extension(store): Extension<Arc<dyn SecretStore>>,
+    blobs: Option<Extension<Arc<dyn BlobStore>>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(management_token) = management_token(&headers) else {
+        return missing_management_token();
+    };
+    let blobs = blobs.as_ref().map(|Extension(blobs)| blobs.as_ref());
+    match revoke_handler(store.as_ref(), blobs, id, management_token).await {
+        Ok(resp) => Json(resp).into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// update_expiry_handler is used for PATCH /manage/{id}. Expects a
+/// json body like {"ttl_seconds":3600} or {"expires_at":1700000000}.
+///
+/// # Errors
+/// The token may be missing or wrong, the expiry invalid, or the
+/// store can fail.
+async fn rest_update_expiry_handler(
+    Extension(store): Extension<Arc<dyn SecretStore>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    expiry: Result<Json<Expiry>, JsonRejection>,
+) -> Response {
+    let Json(expiry) = match expiry {
+        Ok(expiry) => expiry,
+        Err(rejection) => return invalid_json(rejection),
+    };
+    let Some(management_token) = management_token(&headers) else {
+        return missing_management_token();
+    };
+    match update_expiry_handler(store.as_ref(), id, management_token, expiry).await {
+        Ok(resp) => Json(resp).into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// file_upload_handler is used for POST /files. Expects a
+/// multipart/form-data body with a "file" and a "key" field, optionally
+/// with "ttl_seconds", "expires_at", "max_attempts" and
+/// "on_max_attempts" fields. The file is sealed and stored chunk by
+/// chunk as it arrives. Returns a UUID that is needed for the download.
+///
+/// # Errors
+/// The body may be invalid, miss the file or key, or be too large, and
+/// sealing or storing can fail.
+async fn rest_file_upload_handler(
+    Extension(store): Extension<Arc<dyn SecretStore>>,
+    Extension(blobs): Extension<Arc<dyn BlobStore>>,
+    Extension(crypto_config): Extension<Arc<CryptoConfig>>,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Response {
+    let multipart = match multipart {
+        Ok(multipart) => multipart,
+        Err(rejection) => {
+            return CipherLinkError::BadRequest(rejection.body_text()).into_response();
+        }
+    };
+    match upload_file(store.as_ref(), blobs, &crypto_config, multipart).await {
+        Ok(resp) => Json(EncryptApiResponse::Ok(resp)).into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Reads the fields of a /files body, streaming the file into a
+/// `FileUpload`. Unknown fields are skipped.
+async fn upload_file(
+    store: &dyn SecretStore,
+    blobs: Arc<dyn BlobStore>,
+    crypto_config: &CryptoConfig,
+    mut multipart: Multipart,
+) -> Result<EncryptResponse, CipherLinkError> {
+    let mut upload = None;
+    let mut key = None;
+    let mut expiry = Expiry::default();
+    let mut max_attempts = None;
+    let mut on_max_attempts = None;
+    while let Some(mut field) = multipart.next_field().await.map_err(multipart_error)? {
+        match field.name().unwrap_or_default() {
+            "file" => {
+                if upload.is_some() {
+                    return Err(CipherLinkError::BadRequest(
+                        "Only one file can be uploaded".into(),
+                    ));
+                }
+                let name = field.file_name().map(str::to_string);
+                let content_type = field.content_type().map(str::to_string);
+                let mut file = FileUpload::new(
+                    blobs.clone(),
+                    crypto_config,
+                    name.as_deref(),
+                    content_type.as_deref(),
+                );
+                while let Some(bytes) = field.chunk().await.map_err(multipart_error)? {
+                    file.write(&bytes).await?;
+                }
+                upload = Some(file);
+            }
+            "key" => key = Some(SecretString::from(text_field(field).await?)),
+            "ttl_seconds" => expiry.ttl_seconds = Some(number_field(field).await?),
+            "expires_at" => expiry.expires_at = Some(number_field(field).await?),
+            "max_attempts" => max_attempts = Some(number_field(field).await?),
+            "on_max_attempts" => {
+                let name = text_field(field).await?;
+                let lockout = Lockout::from_name(&name).ok_or_else(|| {
+                    CipherLinkError::BadRequest("on_max_attempts must be lock or destroy".into())
+                })?;
+                on_max_attempts = Some(lockout);
+            }
+            _ => {}
+        }
+    }
+
+    let upload = upload.ok_or_else(|| CipherLinkError::BadRequest("Missing file".into()))?;
+    let key = key.ok_or_else(|| CipherLinkError::BadRequest("Missing key".into()))?;
+    let request = FileUploadRequest {
+        key,
+        max_attempts,
+        on_max_attempts,
+        expiry,
+    };
+    upload.finish(store, crypto_config, request).await
+}
+
+async fn text_field(field: Field<'_>) -> Result<String, CipherLinkError> {
+    field.text().await.map_err(multipart_error)
+}
+
+async fn number_field<T: std::str::FromStr>(field: Field<'_>) -> Result<T, CipherLinkError> {
+    let name = field.name().unwrap_or_default().to_string();
+    text_field(field)
+        .await?
+        .trim()
+        .parse()
+        .map_err(|_| CipherLinkError::BadRequest(format!("{} must be a number", name)))
+}
+
+fn multipart_error(err: MultipartError) -> CipherLinkError {
+    if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return CipherLinkError::TooLarge(format!("Files are limited to {} bytes", MAX_FILE_LEN));
+    }
+    CipherLinkError::BadRequest(err.body_text())
+}
+
+/// file_download_handler is used for GET /files/{id} with the key in
+/// an `Authorization: Bearer <key>` header. Streams the file as an
+/// attachment, then deletes it.
+///
+/// # Errors
+/// Same as rest_decrypt_header_handler, or the record is not a file.
+async fn rest_file_download_handler(
+    Extension(store): Extension<Arc<dyn SecretStore>>,
+    Extension(blobs): Extension<Arc<dyn BlobStore>>,
+    Extension(crypto_config): Extension<Arc<CryptoConfig>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(key) = authorization_key(&headers) else {
+        return CipherLinkError::BadRequest("Missing key".into()).into_response();
+    };
+    download_file(store.as_ref(), blobs, &crypto_config, id, key).await
+}
+
+/// file_unlock_handler is used for POST /files/{id} with the key in a
+/// form or JSON body, as the unlock page sends it, so browsers can
+/// download files too. Answers like rest_file_download_handler.
+///
+/// # Errors
+/// Same as rest_file_download_handler, or the body has no key.
+async fn rest_file_unlock_handler(
+    Extension(store): Extension<Arc<dyn SecretStore>>,
+    Extension(blobs): Extension<Arc<dyn BlobStore>>,
+    Extension(crypto_config): Extension<Arc<CryptoConfig>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let key = match parse_decrypt_payload(content_type, &body) {
+        Ok(DecryptPayload::Key(req)) => req.key,
+        Ok(_) => {
+            return CipherLinkError::BadRequest("Files are unlocked with a key".into())
+                .into_response();
+        }
+        Err(err) => return err.into_response(),
+    };
+    download_file(store.as_ref(), blobs, &crypto_config, id, key).await
+}
+
+/// Opens a file with `key` and streams it as an attachment.
+async fn download_file(
+    store: &dyn SecretStore,
+    blobs: Arc<dyn BlobStore>,
+    crypto_config: &CryptoConfig,
+    id: String,
+    key: SecretString,
+) -> Response {
+    let download = match file_download_handler(store, blobs, crypto_config, id, key).await {
+        Ok(download) => download,
+        Err(err) => return err.into_response(),
+    };
+    let headers = [
+        (header::CONTENT_TYPE, download.content_type.clone()),
+        (header::CONTENT_LENGTH, download.size.to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", download.name),
+        ),
+        (header::CACHE_CONTROL, "no-store".to_string()),
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+    ];
+    let chunks = futures_util::stream::unfold(download, |mut download| async move {
+        let chunk = download.next_chunk().await.transpose()?;
+        Some((
+            chunk.map(|mut chunk| Bytes::from(std::mem::take(&mut *chunk))),
+            download,
+        ))
+    });
+    (headers, Body::from_stream(chunks)).into_response()
+}
+
+/// The key sent as `Authorization: Bearer <key>`.
+fn authorization_key(headers: &HeaderMap) -> Option<SecretString> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(bearer_key)
+}
+
+/// Reads the management token header.
+fn management_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(MANAGEMENT_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// The response for a missing management token header.
+fn missing_management_token() -> Response {
+    CipherLinkError::Forbidden("Missing management token".into()).into_response()
+}
+
+/// The response for a body axum could not parse as JSON.
+fn invalid_json(rejection: JsonRejection) -> Response {
+    CipherLinkError::BadRequest(rejection.body_text()).into_response()
+}
+
+impl IntoResponse for CipherLinkError {
+    /// Answers with the error's status and JSON body, logging the
+    /// detail of server errors the body leaves out.
+    fn into_response(self) -> Response {
+        let status =
+            StatusCode::from_u16(self.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        if status.is_server_error() {
+            eprintln!("{}: {}", self.code(), self);
+        }
+        (status, Json(self.body())).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        crypto::KdfParams,
+        store::MemoryStore,
+        types::{EncryptRequest, Expiry},
+    };
+
+    fn test_app() -> (Arc<dyn SecretStore>, CryptoConfig) {
+        let crypto_config = CryptoConfig {
+            kdf_params: KdfParams {
+                m_cost: 64,
+                t_cost: 1,
+                p_cost: 1,
+            },
+            ..CryptoConfig::default()
+        };
+        (Arc::new(MemoryStore::new()), crypto_config)
+    }
+
+    fn test_blobs() -> Arc<dyn BlobStore> {
+        let root = std::env::temp_dir().join(format!("cipherlink-{}", uuid::Uuid::new_v4()));
+        Arc::new(blob::FsBlobStore::new(root))
+    }
+
+    /// Runs a request through the router as server mode receives it,
+    /// returning the status, headers and body.
+    async fn send(app: &Router, req: Request<Body>) -> (StatusCode, HeaderMap, Bytes) {
+        into_parts(app.clone().oneshot(req).await.unwrap()).await
+    }
+
+    /// Runs an API Gateway event through the router as lambda_http
+    /// hands it over in Lambda mode.
+    async fn send_event(
+        app: &Router,
+        mut event: serde_json::Value,
+    ) -> (StatusCode, HeaderMap, Bytes) {
+        // lambda_http only accepts REST events with a request context.
+        event["requestContext"] = json!({"httpMethod": event["httpMethod"]});
+        let req = lambda_http::request::from_str(&event.to_string()).expect("invalid event");
+        into_parts(app.clone().oneshot(req).await.unwrap()).await
+    }
+
+    async fn into_parts(resp: Response) -> (StatusCode, HeaderMap, Bytes) {
+        let (parts, body) = resp.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        (parts.status, parts.headers, body)
+    }
+
+    async fn encrypt_link(
+        store: &dyn SecretStore,
+        crypto_config: &CryptoConfig,
+        plain_text: &str,
+        kind: Option<SecretKind>,
+    ) -> String {
+        let req = EncryptRequest {
+            plain_text: plain_text.to_string().into(),
+            key: "music".to_string().into(),
+            kind,
+            max_views: None,
+            max_attempts: None,
+            on_max_attempts: None,
+            expiry: Expiry::default(),
+        };
+        encrypt_handler(store, crypto_config, req).await.unwrap().id
+    }
+
+    #[tokio::test]
+    async fn test_modes_respond_identically() {
+        let (store, crypto_config) = test_app();
+        let rest_id = encrypt_link(store.as_ref(), &crypto_config, "http://yahoo.com", None).await;
+        let lambda_id =
+            encrypt_link(store.as_ref(), &crypto_config, "http://yahoo.com", None).await;
+        let app = router(store, Some(test_blobs()), crypto_config, false);
+
+        let cases = [
+            ("GET", "/health".to_string(), None, ""),
+            ("POST", "/encrypt".to_string(), None, "{"),
+            (
+                "GET",
+                "/decrypt/missing".to_string(),
+                Some("Bearer music"),
+                "",
+            ),
+            ("GET", format!("/decrypt/{}/music", rest_id), None, ""),
+            ("GET", "/nowhere".to_string(), None, ""),
+        ];
+        for (method, path, authorization, body) in cases {
+            let mut req = Request::builder()
+                .method(method)
+                .uri(&path)
+                .header(header::CONTENT_TYPE, "application/json");
+            let mut headers = json!({"content-type": "application/json"});
+            if let Some(authorization) = authorization {
+                req = req.header(header::AUTHORIZATION, authorization);
+                headers["authorization"] = json!(authorization);
+            }
+            let rest = send(&app, req.body(Body::from(body)).unwrap()).await;
+            let event = json!({
+                "httpMethod": method,
+                "path": path,
+                "headers": headers,
+                "body": body,
+            });
+            let lambda = send_event(&app, event).await;
+            assert_eq!(rest, lambda, "{} {}", method, path);
+        }
+
+        // Consumes each link once, with the key in a body.
+        let req = Request::builder()
+            .method("POST")
+            .uri(format!("/decrypt/{}", rest_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"key":"music"}"#))
+            .unwrap();
+        let rest = send(&app, req).await;
+        let event = json!({
+            "httpMethod": "POST",
+            "path": format!("/decrypt/{}", lambda_id),
+            "headers": {"content-type": "application/json"},
+            "body": r#"{"key":"music"}"#,
+        });
+        let lambda = send_event(&app, event).await;
+        assert_eq!(StatusCode::SEE_OTHER, rest.0);
+        assert_eq!(rest, lambda);
+    }
+
+    #[tokio::test]
+    async fn test_secret_kinds() {
+        let (store, crypto_config) = test_app();
+        let text_id = encrypt_link(
+            store.as_ref(),
+            &crypto_config,
+            "<b>hunter2</b>",
+            Some(SecretKind::Text),
+        )
+        .await;
+        let json_id = encrypt_link(
+            store.as_ref(),
+            &crypto_config,
+            r#"{"token":"abc"}"#,
+            Some(SecretKind::Json),
+        )
+        .await;
+        let app = router(store, Some(test_blobs()), crypto_config, false);
+
+        let decrypt = |id: &str| {
+            Request::builder()
+                .uri(format!("/decrypt/{}", id))
+                .header(header::AUTHORIZATION, "Bearer music")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let (status, headers, body) = send(&app, decrypt(&text_id)).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("no-store", headers[header::CACHE_CONTROL]);
+        let page = String::from_utf8(body.to_vec()).unwrap();
+        assert!(page.contains("&lt;b&gt;hunter2&lt;/b&gt;"));
+
+        let (status, headers, body) = send(&app, decrypt(&json_id)).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("0", headers[VIEWS_LEFT_HEADER]);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json!({"secret": {"token": "abc"}, "views_left": 0}), body);
+
+        // Both were one-time.
+        let (status, _, _) = send(&app, decrypt(&text_id)).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+    }
+
+    #[tokio::test]
+    async fn test_files_need_a_blob_store() {
+        let (store, crypto_config) = test_app();
+        let app = router(store, None, crypto_config, false);
+        let req = Request::builder()
+            .method("POST")
+            .uri("/files")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, _) = send(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+    }
+
+    /// Uploads a text file sealed under "music" and returns its id.
+    async fn upload_file(app: &Router) -> String {
+        let boundary = "cipherlink";
+        let body = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"key\"\r\n\r\nmusic\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"token.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\nhunter2\r\n--{b}--\r\n",
+            b = boundary
+        );
+        let req = Request::builder()
+            .method("POST")
+            .uri("/files")
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(Body::from(body))
+            .unwrap();
+        let (status, _, body) = send(app, req).await;
+        assert_eq!(StatusCode::OK, status);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        body["data"]["id"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_file_upload_and_download() {
+        let (store, crypto_config) = test_app();
+        let app = router(store, Some(test_blobs()), crypto_config, false);
+        let id = upload_file(&app).await;
+
+        let download = |id: &str| {
+            Request::builder()
+                .uri(format!("/files/{}", id))
+                .header(header::AUTHORIZATION, "Bearer music")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let (status, headers, body) = send(&app, download(&id)).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            "attachment; filename=\"token.txt\"",
+            headers[header::CONTENT_DISPOSITION]
+        );
+        assert_eq!("hunter2", body);
+
+        // Files are one-time.
+        let (status, _, _) = send(&app, download(&id)).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+    }
+
+    #[tokio::test]
+    async fn test_file_unlock_page() {
+        let (store, crypto_config) = test_app();
+        let app = router(store, Some(test_blobs()), crypto_config, false);
+        let id = upload_file(&app).await;
+
+        let req = Request::builder()
+            .uri(format!("/unlock/files/{}", id))
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, _) = send(&app, req).await;
+        assert_eq!(StatusCode::OK, status);
+
+        // What the page posts.
+        let req = Request::builder()
+            .method("POST")
+            .uri(format!("/files/{}", id))
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from("key=music"))
+            .unwrap();
+        let (status, headers, body) = send(&app, req).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            "attachment; filename=\"token.txt\"",
+            headers[header::CONTENT_DISPOSITION]
+        );
+        assert_eq!("hunter2", body);
     }
 }
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use aws_sdk_dynamodb::types::AttributeValue;
+
+use crate::{
+    crypto::{EncryptData, envelope},
+    db::DynamoDBClient,
+    error::CipherLinkError,
+    store::{AttemptOutcome, Entry, Lockout, SecretStore},
+    transformer::{
+        ENVELOPE_ATTRIBUTE, EXPIRES_AT_ATTRIBUTE, FAILED_ATTEMPTS_ATTRIBUTE,
+        LOCKED_UNTIL_ATTRIBUTE, VIEWS_LEFT_ATTRIBUTE, entry_to_item, item_to_attempts,
+        item_to_entry,
+    },
+};
+
+/// Name of the hash key attribute.
+const KEY_ATTRIBUTE: &str = "id";
+
+/// Stores records as items in a DynamoDB table keyed by id.
+pub struct DynamoStore {
+    client: DynamoDBClient,
+    table: String,
+}
+
+impl DynamoStore {
+    pub fn new(client: DynamoDBClient, table: &str) -> Self {
+        DynamoStore {
+            client,
+            table: table.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl SecretStore for DynamoStore {
+    async fn setup(&self) -> Result<(), CipherLinkError> {
+        self.client
+            .init_table(&self.table, KEY_ATTRIBUTE, EXPIRES_AT_ATTRIBUTE)
+            .await
+            .map_err(|e| CipherLinkError::Unavailable(format!("Failed to create table: {}", e)))
+    }
+
+    async fn put(&self, id: &str, entry: Entry) -> Result<(), CipherLinkError> {
+        self.client
+            .insert(&self.table, entry_to_item(id, &entry))
+            .await
+            .map_err(|e| CipherLinkError::Unavailable(e.to_string()))
+    }
+
+    async fn get(&self, id: &str) -> Result<Entry, CipherLinkError> {
+        let item = self.client.get(&self.table, KEY_ATTRIBUTE, id).await?;
+        item_to_entry(&item)
+    }
+
+    async fn swap_envelope(
+        &self,
+        id: &str,
+        current: &EncryptData,
+        data: &EncryptData,
+    ) -> Result<(), CipherLinkError> {
+        self.client
+            .swap_attribute(
+                &self.table,
+                KEY_ATTRIBUTE,
+                id,
+                ENVELOPE_ATTRIBUTE,
+                AttributeValue::B(envelope::to_bytes(current).into()),
+                AttributeValue::B(envelope::to_bytes(data).into()),
+            )
+            .await
+    }
+
+    async fn take(&self, id: &str) -> Result<Entry, CipherLinkError> {
+        let item = self.client.take(&self.table, KEY_ATTRIBUTE, id).await?;
+        item_to_entry(&item)
+    }
+
+    async fn consume(&self, id: &str) -> Result<u32, CipherLinkError> {
+        if let Some(views_left) = self
+            .client
+            .decrement(&self.table, KEY_ATTRIBUTE, id, VIEWS_LEFT_ATTRIBUTE)
+            .await?
+        {
+            return Ok(views_left);
+        }
+        // The last view, so whoever deletes the item gets it.
+        self.client.take(&self.table, KEY_ATTRIBUTE, id).await?;
+        Ok(0)
+    }
+
+    async fn fail_attempt(
+        &self,
+        id: &str,
+        lock_until: u64,
+    ) -> Result<AttemptOutcome, CipherLinkError> {
+        // The limit never changes, so it can be read first.
+        let item = self.client.get(&self.table, KEY_ATTRIBUTE, id).await?;
+        let limit = item_to_attempts(&item)?.limit;
+        let below = limit.map_or(u32::MAX, |l| l.max_attempts.saturating_sub(1));
+        if self
+            .client
+            .increment_below(
+                &self.table,
+                KEY_ATTRIBUTE,
+                id,
+                FAILED_ATTEMPTS_ATTRIBUTE,
+                below,
+            )
+            .await?
+        {
+            return Ok(AttemptOutcome::Counted);
+        }
+        // This attempt reached the limit, or the item is gone and the
+        // calls below fail.
+        match limit.map(|l| l.lockout) {
+            Some(Lockout::Destroy) => {
+                self.client.take(&self.table, KEY_ATTRIBUTE, id).await?;
+                Ok(AttemptOutcome::Destroyed)
+            }
+            _ => {
+                let attributes = HashMap::from([
+                    (
+                        FAILED_ATTEMPTS_ATTRIBUTE.to_string(),
+                        AttributeValue::N("0".into()),
+                    ),
+                    (
+                        LOCKED_UNTIL_ATTRIBUTE.to_string(),
+                        AttributeValue::N(lock_until.to_string()),
+                    ),
+                ]);
+                self.client
+                    .set_attributes(&self.table, KEY_ATTRIBUTE, id, attributes)
+                    .await?;
+                Ok(AttemptOutcome::Locked)
+            }
+        }
+    }
+
+    async fn set_expiry(&self, id: &str, expires_at: u64) -> Result<(), CipherLinkError> {
+        let attributes = HashMap::from([(
+            EXPIRES_AT_ATTRIBUTE.to_string(),
+            AttributeValue::N(expires_at.to_string()),
+        )]);
+        self.client
+            .set_attributes(&self.table, KEY_ATTRIBUTE, id, attributes)
+            .await
+    }
+
+    async fn list(&self) -> Result<Vec<String>, CipherLinkError> {
+        let items = self
+            .client
+            .scan(&self.table)
+            .await
+            .map_err(|e| CipherLinkError::Unavailable(e.to_string()))?;
+        Ok(items
+            .iter()
+            .filter_map(|item| match item.get(KEY_ATTRIBUTE) {
+                Some(AttributeValue::S(id)) => Some(id.clone()),
+                _ => None,
+            })
+            .collect())
+    }
+
+    async fn health(&self) -> Result<(), CipherLinkError> {
+        self.client
+            .check_db()
+            .await
+            .map_err(|e| CipherLinkError::Unavailable(e.to_string()))
+    }
+}
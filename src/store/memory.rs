@@ -0,0 +1,191 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+
+use crate::{
+    crypto::{EncryptData, envelope},
+    error::CipherLinkError,
+    store::{AttemptOutcome, Entry, Record, SecretStore, now},
+};
+
+/// Keeps records in process memory. Nothing survives a restart, so it
+/// is meant for tests and single-node deployments.
+#[derive(Default)]
+pub struct MemoryStore {
+    records: Mutex<HashMap<String, Entry>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SecretStore for MemoryStore {
+    async fn setup(&self) -> Result<(), CipherLinkError> {
+        Ok(())
+    }
+
+    async fn put(&self, id: &str, entry: Entry) -> Result<(), CipherLinkError> {
+        let mut records = self.records.lock().unwrap();
+        // Nothing else sweeps expired entries.
+        let now = now();
+        records.retain(|_, entry| !entry.is_expired(now));
+        records.insert(id.to_string(), entry);
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Entry, CipherLinkError> {
+        self.records
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| CipherLinkError::not_found(id))
+    }
+
+    async fn swap_envelope(
+        &self,
+        id: &str,
+        current: &EncryptData,
+        data: &EncryptData,
+    ) -> Result<(), CipherLinkError> {
+        match self.records.lock().unwrap().get_mut(id) {
+            Some(Entry {
+                record: Record::Server(existing),
+                ..
+            }) if envelope::to_bytes(existing) == envelope::to_bytes(current) => {
+                *existing = data.clone();
+                Ok(())
+            }
+            _ => Err(CipherLinkError::not_found(id)),
+        }
+    }
+
+    async fn take(&self, id: &str) -> Result<Entry, CipherLinkError> {
+        self.records
+            .lock()
+            .unwrap()
+            .remove(id)
+            .ok_or_else(|| CipherLinkError::not_found(id))
+    }
+
+    async fn consume(&self, id: &str) -> Result<u32, CipherLinkError> {
+        let mut records = self.records.lock().unwrap();
+        let entry = records
+            .get_mut(id)
+            .ok_or_else(|| CipherLinkError::not_found(id))?;
+        entry.views_left = entry.views_left.saturating_sub(1);
+        let views_left = entry.views_left;
+        if views_left == 0 {
+            records.remove(id);
+        }
+        Ok(views_left)
+    }
+
+    async fn fail_attempt(
+        &self,
+        id: &str,
+        lock_until: u64,
+    ) -> Result<AttemptOutcome, CipherLinkError> {
+        let mut records = self.records.lock().unwrap();
+        let entry = records
+            .get_mut(id)
+            .ok_or_else(|| CipherLinkError::not_found(id))?;
+        let outcome = entry.attempts.fail(lock_until);
+        if outcome == AttemptOutcome::Destroyed {
+            records.remove(id);
+        }
+        Ok(outcome)
+    }
+
+    async fn set_expiry(&self, id: &str, expires_at: u64) -> Result<(), CipherLinkError> {
+        match self.records.lock().unwrap().get_mut(id) {
+            Some(entry) => {
+                entry.expires_at = Some(expires_at);
+                Ok(())
+            }
+            None => Err(CipherLinkError::not_found(id)),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>, CipherLinkError> {
+        Ok(self.records.lock().unwrap().keys().cloned().collect())
+    }
+
+    async fn health(&self) -> Result<(), CipherLinkError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{CryptoConfig, encrypt};
+
+    fn client_entry(ciphertext: u8) -> Entry {
+        Entry::new(Record::Client(vec![ciphertext]))
+    }
+
+    #[tokio::test]
+    async fn test_put_get_delete() {
+        let store = MemoryStore::new();
+        store.put("a", client_entry(1)).await.unwrap();
+        assert!(
+            matches!(store.get("a").await, Ok(Entry { record: Record::Client(c), .. }) if c == vec![1])
+        );
+        assert_eq!(vec!["a".to_string()], store.list().await.unwrap());
+
+        assert!(store.take("a").await.is_ok());
+        assert!(store.take("a").await.is_err());
+        assert!(store.get("a").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_swap_envelope() {
+        let store = MemoryStore::new();
+        let config = CryptoConfig::default();
+        let old = encrypt("abc", "key", "a", &config).unwrap();
+        let new = encrypt("abc", "key", "a", &config).unwrap();
+        let mut entry = Entry::new(Record::Server(old.clone()));
+        entry.views_left = 2;
+        store.put("a", entry).await.unwrap();
+        // A view and a failed attempt land after the rotation read.
+        store.consume("a").await.unwrap();
+        store.fail_attempt("a", 0).await.unwrap();
+
+        store.swap_envelope("a", &old, &new).await.unwrap();
+        let entry = store.get("a").await.unwrap();
+        assert!(matches!(entry.record, Record::Server(got) if got.salt == new.salt));
+        assert_eq!(1, entry.views_left);
+        assert_eq!(1, entry.attempts.failed);
+        // The envelope is no longer the old one.
+        assert!(store.swap_envelope("a", &old, &new).await.is_err());
+        assert!(store.swap_envelope("b", &old, &new).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_consume() {
+        let store = MemoryStore::new();
+        let mut entry = client_entry(1);
+        entry.views_left = 2;
+        store.put("a", entry).await.unwrap();
+        assert_eq!(Ok(1), store.consume("a").await);
+        assert_eq!(Ok(0), store.consume("a").await);
+        assert!(store.consume("a").await.is_err());
+        assert!(store.get("a").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_put_purges_expired() {
+        let store = MemoryStore::new();
+        let mut expired = client_entry(1);
+        expired.expires_at = Some(now() - 1);
+        store.put("a", expired).await.unwrap();
+        assert!(store.get("a").await.unwrap().is_expired(now()));
+
+        store.put("b", client_entry(2)).await.unwrap();
+        assert_eq!(vec!["b".to_string()], store.list().await.unwrap());
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::{app_config::AppConfig, crypto::EncryptData, db, error::CipherLinkError};
+
+mod dynamodb;
+mod memory;
+mod redis;
+mod sqlite;
+
+pub use dynamodb::DynamoStore;
+pub use memory::MemoryStore;
+pub use redis::RedisStore;
+pub use sqlite::SqliteStore;
+
+/// Which backend records are kept in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StoreKind {
+    #[default]
+    DynamoDb,
+    /// Process memory, lost on restart.
+    Memory,
+    /// A local SQLite database file.
+    Sqlite,
+    /// A Redis or Valkey compatible server.
+    Redis,
+}
+
+impl StoreKind {
+    /// Parses a backend from its config name, e.g. `memory`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dynamodb" => Some(StoreKind::DynamoDb),
+            "memory" => Some(StoreKind::Memory),
+            "sqlite" => Some(StoreKind::Sqlite),
+            "redis" => Some(StoreKind::Redis),
+            _ => None,
+        }
+    }
+}
+
+/// What is stored for one link id.
+#[derive(Clone)]
+pub enum Record {
+    /// Sealed server-side with `crypto::encrypt` or one of its variants.
+    Server(EncryptData),
+    /// A zero-knowledge envelope the server can't open.
+    Client(Vec<u8>),
+}
+
+/// What a server-side secret is, which decides how it is handed out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretKind {
+    /// A URL the link redirects to.
+    #[default]
+    Redirect,
+    /// Text shown once on a page, e.g. a password.
+    Text,
+    /// A JSON value returned as is, e.g. API credentials.
+    Json,
+    /// A file attachment. The plaintext holds the key to its chunks in
+    /// the blob store, it is only handed out by streaming the file.
+    File,
+}
+
+impl SecretKind {
+    /// The name stored with the record.
+    pub fn name(self) -> &'static str {
+        match self {
+            SecretKind::Redirect => "redirect",
+            SecretKind::Text => "text",
+            SecretKind::Json => "json",
+            SecretKind::File => "file",
+        }
+    }
+
+    /// Parses a kind from its stored name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "redirect" => Some(SecretKind::Redirect),
+            "text" => Some(SecretKind::Text),
+            "json" => Some(SecretKind::Json),
+            "file" => Some(SecretKind::File),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to a link once its failed attempts reach the limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lockout {
+    /// Refuse every attempt for a cooldown window, then start over.
+    #[default]
+    Lock,
+    /// Delete the record.
+    Destroy,
+}
+
+impl Lockout {
+    /// The name stored with the record.
+    pub fn name(self) -> &'static str {
+        match self {
+            Lockout::Lock => "lock",
+            Lockout::Destroy => "destroy",
+        }
+    }
+
+    /// Parses a policy from its stored name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "lock" => Some(Lockout::Lock),
+            "destroy" => Some(Lockout::Destroy),
+            _ => None,
+        }
+    }
+}
+
+/// How many failed decrypt attempts a link takes, and what happens
+/// after the last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AttemptLimit {
+    pub max_attempts: u32,
+    pub lockout: Lockout,
+}
+
+/// What a failed attempt did to an entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttemptOutcome {
+    /// Counted, more attempts are left.
+    Counted,
+    /// The limit was reached and the entry is locked.
+    Locked,
+    /// The limit was reached and the entry is deleted.
+    Destroyed,
+}
+
+/// The failed decrypt attempts of an entry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Attempts {
+    /// None for records written before attempts were limited.
+    pub limit: Option<AttemptLimit>,
+    /// Failed attempts since creation or the last lockout.
+    pub failed: u32,
+    /// Unix time in seconds until which every attempt is refused.
+    pub locked_until: Option<u64>,
+}
+
+impl Attempts {
+    /// Whether attempts are refused at unix time `now`.
+    pub fn is_locked(&self, now: u64) -> bool {
+        self.locked_until
+            .is_some_and(|locked_until| locked_until > now)
+    }
+
+    /// Counts a failed attempt. Once the limit is reached the entry is
+    /// locked until `lock_until`, or the caller has to delete it.
+    pub fn fail(&mut self, lock_until: u64) -> AttemptOutcome {
+        self.failed = self.failed.saturating_add(1);
+        let Some(limit) = self.limit else {
+            return AttemptOutcome::Counted;
+        };
+        if self.failed < limit.max_attempts {
+            return AttemptOutcome::Counted;
+        }
+        match limit.lockout {
+            Lockout::Lock => {
+                self.failed = 0;
+                self.locked_until = Some(lock_until);
+                AttemptOutcome::Locked
+            }
+            Lockout::Destroy => AttemptOutcome::Destroyed,
+        }
+    }
+}
+
+/// A record and what the store keeps about it.
+#[derive(Clone)]
+pub struct Entry {
+    pub record: Record,
+    /// How the plaintext is handed out, a redirect for records written
+    /// before there were other kinds.
+    pub kind: SecretKind,
+    /// Unix time in seconds after which the link is gone.
+    pub expires_at: Option<u64>,
+    /// How many more times the link can be opened, at least 1.
+    pub views_left: u32,
+    pub attempts: Attempts,
+    /// Hash of the creator's management token, None for records
+    /// written before links could be managed.
+    pub management_hash: Option<Vec<u8>>,
+}
+
+impl Entry {
+    /// A one-time redirect entry that never expires.
+    pub fn new(record: Record) -> Self {
+        Entry {
+            record,
+            kind: SecretKind::default(),
+            expires_at: None,
+            views_left: 1,
+            attempts: Attempts::default(),
+            management_hash: None,
+        }
+    }
+
+    /// Whether the entry expired at unix time `now`.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// The current unix time in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Where records live. Handlers only talk to this trait, so they are
+/// not tied to a database.
+#[async_trait]
+pub trait SecretStore: Send + Sync {
+    /// Creates whatever the backend needs before first use, e.g. a table.
+    ///
+    /// # Errors
+    /// Errors if the backend rejects it.
+    async fn setup(&self) -> Result<(), CipherLinkError>;
+
+    /// Stores a new entry under `id`. Backends with native expiry drop
+    /// it once it expires, the others keep it until it is purged, so
+    /// callers check `Entry::is_expired` either way.
+    ///
+    /// # Errors
+    /// Errors if the backend write fails.
+    async fn put(&self, id: &str, entry: Entry) -> Result<(), CipherLinkError>;
+
+    /// Returns the entry stored under `id`.
+    ///
+    /// # Errors
+    /// Errors with `CipherLinkError::NotFound` if there is no such entry, it can't be decoded or the
+    /// backend read fails.
+    async fn get(&self, id: &str) -> Result<Entry, CipherLinkError>;
+
+    /// Swaps the envelope of the server-side record under `id` from
+    /// `current` to `data` in one atomic step, e.g. to re-wrap its data
+    /// key. Views, attempts and expiry are left alone, so concurrent
+    /// views and failed attempts are not rolled back.
+    ///
+    /// # Errors
+    /// Errors if there is no such entry, its envelope is no longer
+    /// `current` or the backend write fails.
+    async fn swap_envelope(
+        &self,
+        id: &str,
+        current: &EncryptData,
+        data: &EncryptData,
+    ) -> Result<(), CipherLinkError>;
+
+    /// Removes the entry under `id` and returns it in one atomic step,
+    /// so concurrent callers can't both get it.
+    ///
+    /// # Errors
+    /// Errors if there is no such entry, it can't be decoded or the
+    /// backend delete fails.
+    async fn take(&self, id: &str) -> Result<Entry, CipherLinkError>;
+
+    /// Uses up one view of the entry under `id` in one atomic step,
+    /// deleting it with the last view. Returns the views left.
+    ///
+    /// # Errors
+    /// Errors if there is no such entry, e.g. a concurrent caller used
+    /// the last view, or the backend write fails.
+    async fn consume(&self, id: &str) -> Result<u32, CipherLinkError>;
+
+    /// Counts a failed decrypt attempt on the entry under `id` in one
+    /// atomic step, locking it until `lock_until` or deleting it once
+    /// it reaches its attempt limit.
+    ///
+    /// # Errors
+    /// Errors if there is no such entry or the backend write fails.
+    async fn fail_attempt(
+        &self,
+        id: &str,
+        lock_until: u64,
+    ) -> Result<AttemptOutcome, CipherLinkError>;
+
+    /// Moves the expiry of the entry under `id` to unix time
+    /// `expires_at`, leaving the rest of it alone.
+    ///
+    /// # Errors
+    /// Errors if there is no such entry or the backend write fails.
+    async fn set_expiry(&self, id: &str, expires_at: u64) -> Result<(), CipherLinkError>;
+
+    /// Returns the ids of every stored record.
+    ///
+    /// # Errors
+    /// Errors if the backend read fails.
+    async fn list(&self) -> Result<Vec<String>, CipherLinkError>;
+
+    /// Checks the backend is reachable.
+    ///
+    /// # Errors
+    /// Errors if it is not.
+    async fn health(&self) -> Result<(), CipherLinkError>;
+}
+
+/// Builds the store described by the config.
+pub async fn init(config: &AppConfig) -> Arc<dyn SecretStore> {
+    match config.store {
+        StoreKind::DynamoDb => {
+            let client = db::init(&config.db_url, &config.region).await;
+            Arc::new(DynamoStore::new(client, &config.db_table))
+        }
+        StoreKind::Memory => Arc::new(MemoryStore::new()),
+        StoreKind::Sqlite => Arc::new(
+            SqliteStore::open(&config.sqlite_path)
+                .unwrap_or_else(|e| panic!("unable to open CONFIG_SQLITE_PATH: {}", e)),
+        ),
+        StoreKind::Redis => Arc::new(
+            RedisStore::connect(&config.redis_url)
+                .await
+                .unwrap_or_else(|e| panic!("unable to connect to CONFIG_REDIS_URL: {}", e)),
+        ),
+    }
+}
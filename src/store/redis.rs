@@ -0,0 +1,569 @@
+use std::{
+    sync::LazyLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use redis::{AsyncIter, Client, Script, aio::ConnectionManager};
+
+use crate::{
+    crypto::{EncryptData, envelope},
+    error::CipherLinkError,
+    store::{
+        AttemptLimit, AttemptOutcome, Attempts, Entry, Lockout, Record, SecretKind, SecretStore,
+    },
+    transformer::{bytes_to_recipients, recipients_to_bytes},
+};
+
+/// Prefix of every key, so the store can share a database.
+const KEY_PREFIX: &str = "cipherlink:";
+
+/// Hash fields of an entry. The scripts below use the names as is.
+const RECORD_FIELD: &str = "record";
+const VIEWS_LEFT_FIELD: &str = "views_left";
+const MAX_ATTEMPTS_FIELD: &str = "max_attempts";
+const LOCKOUT_FIELD: &str = "lockout";
+const FAILED_ATTEMPTS_FIELD: &str = "failed_attempts";
+const LOCKED_UNTIL_FIELD: &str = "locked_until";
+const MANAGEMENT_HASH_FIELD: &str = "management_hash";
+const KIND_FIELD: &str = "kind";
+
+/// First byte of a stored record, telling the record kinds apart.
+const KIND_SERVER: u8 = 1;
+const KIND_CLIENT: u8 = 2;
+
+/// Sets the record field of the entry in KEYS[1] to ARGV[2], only if
+/// it still holds ARGV[1]. Returns 1 if it did.
+static SWAP_RECORD: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('HGET', KEYS[1], 'record') ~= ARGV[1] then return 0 end
+        redis.call('HSET', KEYS[1], 'record', ARGV[2])
+        return 1
+        ",
+    )
+});
+
+/// Uses up one view of the entry in KEYS[1], deleting it with the
+/// last. Returns the views left, or -1 if there is no such entry.
+static CONSUME: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 0 then return -1 end
+        local left = redis.call('HINCRBY', KEYS[1], 'views_left', -1)
+        if left <= 0 then
+            redis.call('DEL', KEYS[1])
+            return 0
+        end
+        return left
+        ",
+    )
+});
+
+/// Counts a failed attempt on the entry in KEYS[1], locking it until
+/// ARGV[1] or deleting it at the limit. Returns 0 if counted, 1 if
+/// locked, 2 if destroyed or -1 if there is no such entry, the same
+/// logic as `Attempts::fail`.
+static FAIL_ATTEMPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 0 then return -1 end
+        local failed = redis.call('HINCRBY', KEYS[1], 'failed_attempts', 1)
+        local max = tonumber(redis.call('HGET', KEYS[1], 'max_attempts'))
+        if not max or failed < max then return 0 end
+        if redis.call('HGET', KEYS[1], 'lockout') == 'destroy' then
+            redis.call('DEL', KEYS[1])
+            return 2
+        end
+        redis.call('HSET', KEYS[1], 'failed_attempts', 0, 'locked_until', ARGV[1])
+        return 1
+        ",
+    )
+});
+
+/// The fields of an entry as read by HMGET, in `FIELDS` order.
+type Fields = (
+    Option<Vec<u8>>,
+    Option<u32>,
+    Option<u32>,
+    Option<String>,
+    Option<u32>,
+    Option<u64>,
+    Option<Vec<u8>>,
+    Option<String>,
+);
+
+const FIELDS: [&str; 8] = [
+    RECORD_FIELD,
+    VIEWS_LEFT_FIELD,
+    MAX_ATTEMPTS_FIELD,
+    LOCKOUT_FIELD,
+    FAILED_ATTEMPTS_FIELD,
+    LOCKED_UNTIL_FIELD,
+    MANAGEMENT_HASH_FIELD,
+    KIND_FIELD,
+];
+
+/// Stores entries as hashes in a Redis or Valkey compatible server.
+/// Expiry is the key's own, so the server drops expired links. Works
+/// with Redis 6.2 or later, and Valkey.
+pub struct RedisStore {
+    conn: ConnectionManager,
+}
+
+impl RedisStore {
+    /// Connects to the server at `url`, e.g. `redis://127.0.0.1:6379`.
+    ///
+    /// # Errors
+    /// Errors if the url is invalid or the server can't be reached.
+    pub async fn connect(url: &str) -> Result<Self, String> {
+        let client = Client::open(url).map_err(|e| format!("Invalid redis url: {}", e))?;
+        let conn = ConnectionManager::new(client)
+            .await
+            .map_err(|e| format!("Redis connect failed: {}", e))?;
+        Ok(RedisStore { conn })
+    }
+
+    /// Runs a command on a clone of the managed connection.
+    async fn query<T: redis::FromRedisValue>(
+        &self,
+        cmd: &redis::Cmd,
+    ) -> Result<T, CipherLinkError> {
+        cmd.query_async(&mut self.conn.clone())
+            .await
+            .map_err(unavailable)
+    }
+
+    /// Reads the entry of `id` in a transaction, deleting it as well
+    /// if `delete` is set.
+    async fn read_entry(&self, id: &str, delete: bool) -> Result<Entry, CipherLinkError> {
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("PTTL")
+            .arg(key(id))
+            .cmd("HMGET")
+            .arg(key(id))
+            .arg(&FIELDS[..]);
+        if delete {
+            pipe.cmd("DEL").arg(key(id)).ignore();
+        }
+        let (ttl_millis, fields): (i64, Fields) = pipe
+            .query_async(&mut self.conn.clone())
+            .await
+            .map_err(unavailable)?;
+        let (
+            value,
+            views_left,
+            max_attempts,
+            lockout,
+            failed_attempts,
+            locked_until,
+            management_hash,
+            kind,
+        ) = fields;
+        let value = value.ok_or_else(|| CipherLinkError::not_found(id))?;
+        let kind = match kind.as_deref() {
+            None => SecretKind::default(),
+            Some(name) => SecretKind::from_name(name)
+                .ok_or_else(|| CipherLinkError::Corrupt(format!("Unknown 'kind' {}", name)))?,
+        };
+        Ok(Entry {
+            record: value_to_record(&value)?,
+            kind,
+            expires_at: expires_at(ttl_millis, now_millis()),
+            views_left: views_left.unwrap_or(1),
+            attempts: Attempts {
+                // An unknown lockout falls back to locking, the safer one.
+                limit: max_attempts.map(|max_attempts| AttemptLimit {
+                    max_attempts,
+                    lockout: lockout
+                        .as_deref()
+                        .and_then(Lockout::from_name)
+                        .unwrap_or_default(),
+                }),
+                failed: failed_attempts.unwrap_or_default(),
+                locked_until,
+            },
+            management_hash,
+        })
+    }
+}
+
+#[async_trait]
+impl SecretStore for RedisStore {
+    async fn setup(&self) -> Result<(), CipherLinkError> {
+        Ok(())
+    }
+
+    async fn put(&self, id: &str, entry: Entry) -> Result<(), CipherLinkError> {
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("DEL")
+            .arg(key(id))
+            .ignore()
+            .cmd("HSET")
+            .arg(key(id))
+            .arg(entry_to_fields(&entry))
+            .ignore();
+        if let Some(expires_at) = entry.expires_at {
+            pipe.cmd("EXPIREAT").arg(key(id)).arg(expires_at).ignore();
+        }
+        pipe.query_async(&mut self.conn.clone())
+            .await
+            .map_err(unavailable)
+    }
+
+    async fn get(&self, id: &str) -> Result<Entry, CipherLinkError> {
+        self.read_entry(id, false).await
+    }
+
+    async fn swap_envelope(
+        &self,
+        id: &str,
+        current: &EncryptData,
+        data: &EncryptData,
+    ) -> Result<(), CipherLinkError> {
+        let swapped: bool = SWAP_RECORD
+            .key(key(id))
+            .arg(server_value(current))
+            .arg(server_value(data))
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .map_err(unavailable)?;
+        if !swapped {
+            return Err(CipherLinkError::not_found(id));
+        }
+        Ok(())
+    }
+
+    async fn take(&self, id: &str) -> Result<Entry, CipherLinkError> {
+        self.read_entry(id, true).await
+    }
+
+    async fn consume(&self, id: &str) -> Result<u32, CipherLinkError> {
+        let views_left: i64 = CONSUME
+            .key(key(id))
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .map_err(unavailable)?;
+        u32::try_from(views_left).map_err(|_| CipherLinkError::not_found(id))
+    }
+
+    async fn fail_attempt(
+        &self,
+        id: &str,
+        lock_until: u64,
+    ) -> Result<AttemptOutcome, CipherLinkError> {
+        let outcome: i64 = FAIL_ATTEMPT
+            .key(key(id))
+            .arg(lock_until)
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .map_err(unavailable)?;
+        match outcome {
+            0 => Ok(AttemptOutcome::Counted),
+            1 => Ok(AttemptOutcome::Locked),
+            2 => Ok(AttemptOutcome::Destroyed),
+            _ => Err(CipherLinkError::not_found(id)),
+        }
+    }
+
+    async fn set_expiry(&self, id: &str, expires_at: u64) -> Result<(), CipherLinkError> {
+        let updated: bool = self
+            .query(redis::cmd("EXPIREAT").arg(key(id)).arg(expires_at))
+            .await?;
+        if !updated {
+            return Err(CipherLinkError::not_found(id));
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>, CipherLinkError> {
+        let mut conn = self.conn.clone();
+        let mut keys: AsyncIter<String> = redis::cmd("SCAN")
+            .cursor_arg(0)
+            .arg("MATCH")
+            .arg(format!("{}*", KEY_PREFIX))
+            .clone()
+            .iter_async(&mut conn)
+            .await
+            .map_err(unavailable)?;
+        let mut ids = Vec::new();
+        while let Some(key) = keys.next_item().await {
+            let key = key.map_err(unavailable)?;
+            ids.push(key.trim_start_matches(KEY_PREFIX).to_string());
+        }
+        Ok(ids)
+    }
+
+    async fn health(&self) -> Result<(), CipherLinkError> {
+        self.query::<String>(&redis::cmd("PING")).await.map(|_| ())
+    }
+}
+
+/// Maps a failed command to `CipherLinkError::Unavailable`.
+fn unavailable(e: redis::RedisError) -> CipherLinkError {
+    CipherLinkError::Unavailable(format!("Redis error: {}", e))
+}
+
+/// The unix time in seconds a key expires at, from its PTTL and the
+/// current time in milliseconds. EXPIRETIME would read it directly but
+/// needs Redis 7. Keys only expire on whole seconds, so rounding to the
+/// nearest one absorbs the time the reply took.
+fn expires_at(ttl_millis: i64, now_millis: u64) -> Option<u64> {
+    // Negative when the key has no expiry.
+    let ttl_millis = u64::try_from(ttl_millis).ok()?;
+    Some((now_millis + ttl_millis + 500) / 1000)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn key(id: &str) -> String {
+    format!("{}{}", KEY_PREFIX, id)
+}
+
+/// The field/value pairs of an entry, leaving out unset ones.
+fn entry_to_fields(entry: &Entry) -> Vec<(&'static str, Vec<u8>)> {
+    let mut fields = vec![
+        (RECORD_FIELD, record_to_value(&entry.record)),
+        (KIND_FIELD, entry.kind.name().as_bytes().to_vec()),
+        (VIEWS_LEFT_FIELD, entry.views_left.to_string().into_bytes()),
+        (
+            FAILED_ATTEMPTS_FIELD,
+            entry.attempts.failed.to_string().into_bytes(),
+        ),
+    ];
+    if let Some(limit) = entry.attempts.limit {
+        fields.push((
+            MAX_ATTEMPTS_FIELD,
+            limit.max_attempts.to_string().into_bytes(),
+        ));
+        fields.push((LOCKOUT_FIELD, limit.lockout.name().as_bytes().to_vec()));
+    }
+    if let Some(locked_until) = entry.attempts.locked_until {
+        fields.push((LOCKED_UNTIL_FIELD, locked_until.to_string().into_bytes()));
+    }
+    if let Some(management_hash) = &entry.management_hash {
+        fields.push((MANAGEMENT_HASH_FIELD, management_hash.clone()));
+    }
+    fields
+}
+
+/// Encodes a record as a kind byte followed by, for server records, the
+/// envelope length (u32 BE), envelope and recipient stanzas, or for
+/// client records the ciphertext.
+fn record_to_value(record: &Record) -> Vec<u8> {
+    match record {
+        Record::Server(data) => server_value(data),
+        Record::Client(ciphertext) => {
+            let mut value = vec![KIND_CLIENT];
+            value.extend_from_slice(ciphertext);
+            value
+        }
+    }
+}
+
+fn server_value(data: &EncryptData) -> Vec<u8> {
+    let envelope = envelope::to_bytes(data);
+    let mut value = vec![KIND_SERVER];
+    value.extend_from_slice(&(envelope.len() as u32).to_be_bytes());
+    value.extend_from_slice(&envelope);
+    value.extend_from_slice(&recipients_to_bytes(&data.recipients));
+    value
+}
+
+fn value_to_record(value: &[u8]) -> Result<Record, CipherLinkError> {
+    match value.split_first() {
+        Some((&KIND_CLIENT, ciphertext)) => Ok(Record::Client(ciphertext.to_vec())),
+        Some((&KIND_SERVER, rest)) if rest.len() >= 4 => {
+            let (len, rest) = rest.split_at(4);
+            let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            if rest.len() < len {
+                return Err(CipherLinkError::Corrupt("Invalid 'envelope'".into()));
+            }
+            let (bytes, recipients) = rest.split_at(len);
+            let mut data =
+                envelope::from_bytes(bytes).map_err(|e| CipherLinkError::Corrupt(e.to_string()))?;
+            data.recipients = bytes_to_recipients(recipients)?;
+            Ok(Record::Server(data))
+        }
+        _ => Err(CipherLinkError::Corrupt("Invalid record".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        process::{Child, Command, Stdio},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        crypto::{CryptoConfig, encrypt},
+        store::now,
+    };
+
+    fn client_entry(ciphertext: u8) -> Entry {
+        Entry::new(Record::Client(vec![ciphertext]))
+    }
+
+    /// A redis-server on a free port, killed on drop.
+    struct RedisServer {
+        child: Child,
+        url: String,
+    }
+
+    impl Drop for RedisServer {
+        fn drop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+
+    /// Launches a throwaway redis-server. The tests that need one are
+    /// ignored by default, run them with redis-server on PATH:
+    /// `cargo test redis -- --ignored`.
+    async fn launch() -> (RedisServer, RedisStore) {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("no free port")
+            .port();
+        let child = Command::new("redis-server")
+            .args([
+                "--port",
+                &port.to_string(),
+                "--save",
+                "",
+                "--appendonly",
+                "no",
+            ])
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("redis-server not found on PATH");
+        let server = RedisServer {
+            child,
+            url: format!("redis://127.0.0.1:{}", port),
+        };
+        for _ in 0..50 {
+            if let Ok(store) = RedisStore::connect(&server.url).await {
+                return (server, store);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("redis-server did not start on {}", server.url);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis-server on PATH"]
+    async fn test_put_get_take() {
+        let (_server, store) = launch().await;
+        store.put("a", client_entry(1)).await.unwrap();
+        assert!(
+            matches!(store.get("a").await, Ok(Entry { record: Record::Client(c), .. }) if c == vec![1])
+        );
+        assert_eq!(vec!["a".to_string()], store.list().await.unwrap());
+
+        assert!(
+            matches!(store.take("a").await, Ok(Entry { record: Record::Client(c), .. }) if c == vec![1])
+        );
+        assert!(store.take("a").await.is_err());
+        assert!(store.health().await.is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis-server on PATH"]
+    async fn test_expiry() {
+        let (_server, store) = launch().await;
+        let expiry = now() + 60;
+        let mut entry = client_entry(1);
+        entry.expires_at = Some(expiry);
+        store.put("a", entry).await.unwrap();
+        assert_eq!(Some(expiry), store.get("a").await.unwrap().expires_at);
+
+        // The server drops keys that expired.
+        let mut entry = client_entry(2);
+        entry.expires_at = Some(now() - 1);
+        store.put("b", entry).await.unwrap();
+        assert!(store.get("b").await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis-server on PATH"]
+    async fn test_swap_envelope() {
+        let (_server, store) = launch().await;
+        let config = CryptoConfig::default();
+        let old = encrypt("abc", "key", "a", &config).unwrap();
+        let new = encrypt("abc", "key", "a", &config).unwrap();
+        let mut entry = Entry::new(Record::Server(old.clone()));
+        entry.views_left = 2;
+        store.put("a", entry).await.unwrap();
+        store.consume("a").await.unwrap();
+        store.fail_attempt("a", 0).await.unwrap();
+
+        store.swap_envelope("a", &old, &new).await.unwrap();
+        let entry = store.get("a").await.unwrap();
+        assert!(matches!(entry.record, Record::Server(got) if got.salt == new.salt));
+        assert_eq!(1, entry.views_left);
+        assert_eq!(1, entry.attempts.failed);
+        assert!(store.swap_envelope("a", &old, &new).await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis-server on PATH"]
+    async fn test_consume() {
+        let (_server, store) = launch().await;
+        let mut entry = client_entry(1);
+        entry.views_left = 2;
+        store.put("a", entry).await.unwrap();
+        assert_eq!(Ok(1), store.consume("a").await);
+        assert_eq!(1, store.get("a").await.unwrap().views_left);
+        assert_eq!(Ok(0), store.consume("a").await);
+        assert!(store.consume("a").await.is_err());
+        assert!(store.get("a").await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis-server on PATH"]
+    async fn test_fail_attempt() {
+        let (_server, store) = launch().await;
+        let mut entry = client_entry(1);
+        entry.attempts.limit = Some(AttemptLimit {
+            max_attempts: 2,
+            lockout: Lockout::Lock,
+        });
+        store.put("a", entry).await.unwrap();
+        assert_eq!(
+            Ok(AttemptOutcome::Counted),
+            store.fail_attempt("a", 5).await
+        );
+        assert_eq!(Ok(AttemptOutcome::Locked), store.fail_attempt("a", 5).await);
+        let attempts = store.get("a").await.unwrap().attempts;
+        assert_eq!(0, attempts.failed);
+        assert_eq!(Some(5), attempts.locked_until);
+    }
+
+    #[test]
+    fn test_expires_at() {
+        assert_eq!(None, expires_at(-1, 1_000_000));
+        assert_eq!(Some(1_060), expires_at(59_950, 1_000_020));
+        assert_eq!(Some(1_060), expires_at(60_000, 1_000_030));
+    }
+
+    #[test]
+    fn test_value_round_trip() {
+        let data = encrypt("abc", "key", "b", &CryptoConfig::default()).unwrap();
+        let value = record_to_value(&Record::Server(data.clone()));
+        let Ok(Record::Server(got)) = value_to_record(&value) else {
+            panic!("expected a server record");
+        };
+        assert_eq!(data.encrypted_text, got.encrypted_text);
+        assert!(value_to_record(&value[..3]).is_err());
+    }
+}
@@ -0,0 +1,492 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::{
+    crypto::{EncryptData, envelope},
+    error::CipherLinkError,
+    store::{
+        AttemptLimit, AttemptOutcome, Attempts, Entry, Lockout, Record, SecretKind, SecretStore,
+        now,
+    },
+    transformer::{bytes_to_recipients, recipients_to_bytes},
+};
+
+/// Schema migrations, applied in order by `setup`. `PRAGMA user_version`
+/// holds how many have run, so only append to this list.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE records (
+        id TEXT PRIMARY KEY NOT NULL,
+        envelope BLOB,
+        recipients BLOB,
+        client_ciphertext BLOB
+    )",
+    "ALTER TABLE records ADD COLUMN expires_at INTEGER",
+    "ALTER TABLE records ADD COLUMN views_left INTEGER NOT NULL DEFAULT 1",
+    "ALTER TABLE records ADD COLUMN max_attempts INTEGER;
+     ALTER TABLE records ADD COLUMN lockout TEXT;
+     ALTER TABLE records ADD COLUMN failed_attempts INTEGER NOT NULL DEFAULT 0;
+     ALTER TABLE records ADD COLUMN locked_until INTEGER",
+    "ALTER TABLE records ADD COLUMN management_hash BLOB",
+    "ALTER TABLE records ADD COLUMN kind TEXT",
+];
+
+/// The columns of a `Row`, in order.
+const COLUMNS: &str = "envelope, recipients, client_ciphertext, expires_at, views_left, \
+                       max_attempts, lockout, failed_attempts, locked_until, management_hash, \
+                       kind";
+
+/// The stored columns of an entry, mirroring the DynamoDB item
+/// attributes.
+struct Row {
+    envelope: Option<Vec<u8>>,
+    recipients: Option<Vec<u8>>,
+    client_ciphertext: Option<Vec<u8>>,
+    expires_at: Option<i64>,
+    views_left: u32,
+    max_attempts: Option<u32>,
+    lockout: Option<String>,
+    failed_attempts: u32,
+    locked_until: Option<i64>,
+    management_hash: Option<Vec<u8>>,
+    kind: Option<String>,
+}
+
+impl Row {
+    /// Runs `sql` with the id as ?1 and the columns as ?2 to ?12, in
+    /// `COLUMNS` order.
+    fn execute(&self, conn: &Connection, sql: &str, id: &str) -> rusqlite::Result<usize> {
+        conn.execute(
+            sql,
+            params![
+                id,
+                self.envelope,
+                self.recipients,
+                self.client_ciphertext,
+                self.expires_at,
+                self.views_left,
+                self.max_attempts,
+                self.lockout,
+                self.failed_attempts,
+                self.locked_until,
+                self.management_hash,
+                self.kind,
+            ],
+        )
+    }
+}
+
+/// Stores records in a SQLite database file.
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating the file if needed. Run
+    /// `setup` to create or migrate the schema.
+    ///
+    /// # Errors
+    /// Errors if the file can't be opened.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let conn = Connection::open(path).map_err(|e| format!("SQLite open failed: {}", e))?;
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` on the connection off the async runtime.
+    async fn run<T, F>(&self, f: F) -> Result<T, CipherLinkError>
+    where
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .map_err(|e| CipherLinkError::Unavailable(e.to_string()))?
+            .map_err(|e| CipherLinkError::Unavailable(format!("SQLite error: {}", e)))
+    }
+}
+
+#[async_trait]
+impl SecretStore for SqliteStore {
+    async fn setup(&self) -> Result<(), CipherLinkError> {
+        self.run(|conn| {
+            let tx = conn.transaction()?;
+            let applied: i64 = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+            for migration in MIGRATIONS.iter().skip(applied as usize) {
+                tx.execute_batch(migration)?;
+            }
+            tx.pragma_update(None, "user_version", MIGRATIONS.len() as i64)?;
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn put(&self, id: &str, entry: Entry) -> Result<(), CipherLinkError> {
+        let id = id.to_string();
+        let row = entry_to_row(&entry);
+        let now = now() as i64;
+        self.run(move |conn| {
+            // Nothing else sweeps expired rows.
+            conn.execute(
+                "DELETE FROM records WHERE expires_at <= ?1",
+                params![now],
+            )?;
+            let sql = format!(
+                "INSERT OR REPLACE INTO records (id, {}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                COLUMNS
+            );
+            row.execute(conn, &sql, &id).map(|_| ())
+        })
+        .await
+    }
+
+    async fn get(&self, id: &str) -> Result<Entry, CipherLinkError> {
+        let owned_id = id.to_string();
+        let row = self
+            .run(move |conn| select(conn, &owned_id))
+            .await?
+            .ok_or_else(|| CipherLinkError::not_found(id))?;
+        row_to_entry(row)
+    }
+
+    async fn swap_envelope(
+        &self,
+        id: &str,
+        current: &EncryptData,
+        data: &EncryptData,
+    ) -> Result<(), CipherLinkError> {
+        let owned_id = id.to_string();
+        let current = envelope::to_bytes(current);
+        let envelope = envelope::to_bytes(data);
+        let updated = self
+            .run(move |conn| {
+                conn.execute(
+                    "UPDATE records SET envelope = ?3 WHERE id = ?1 AND envelope = ?2",
+                    params![owned_id, current, envelope],
+                )
+            })
+            .await?;
+        if updated == 0 {
+            return Err(CipherLinkError::not_found(id));
+        }
+        Ok(())
+    }
+
+    async fn take(&self, id: &str) -> Result<Entry, CipherLinkError> {
+        let owned_id = id.to_string();
+        let row = self
+            .run(move |conn| {
+                let tx = conn.transaction()?;
+                let row = select(&tx, &owned_id)?;
+                tx.execute("DELETE FROM records WHERE id = ?1", params![owned_id])?;
+                tx.commit()?;
+                Ok(row)
+            })
+            .await?
+            .ok_or_else(|| CipherLinkError::not_found(id))?;
+        row_to_entry(row)
+    }
+
+    async fn consume(&self, id: &str) -> Result<u32, CipherLinkError> {
+        let owned_id = id.to_string();
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            let views_left: Option<u32> = tx
+                .query_row(
+                    "UPDATE records SET views_left = views_left - 1 WHERE id = ?1
+                     RETURNING views_left",
+                    params![owned_id],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if views_left == Some(0) {
+                tx.execute("DELETE FROM records WHERE id = ?1", params![owned_id])?;
+            }
+            tx.commit()?;
+            Ok(views_left)
+        })
+        .await?
+        .ok_or_else(|| CipherLinkError::not_found(id))
+    }
+
+    async fn fail_attempt(
+        &self,
+        id: &str,
+        lock_until: u64,
+    ) -> Result<AttemptOutcome, CipherLinkError> {
+        let owned_id = id.to_string();
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            let Some(row) = select(&tx, &owned_id)? else {
+                return Ok(None);
+            };
+            let mut attempts = row_to_attempts(&row);
+            let outcome = attempts.fail(lock_until);
+            if outcome == AttemptOutcome::Destroyed {
+                tx.execute("DELETE FROM records WHERE id = ?1", params![owned_id])?;
+            } else {
+                tx.execute(
+                    "UPDATE records SET failed_attempts = ?2, locked_until = ?3 WHERE id = ?1",
+                    params![
+                        owned_id,
+                        attempts.failed,
+                        attempts.locked_until.map(|t| t as i64)
+                    ],
+                )?;
+            }
+            tx.commit()?;
+            Ok(Some(outcome))
+        })
+        .await?
+        .ok_or_else(|| CipherLinkError::not_found(id))
+    }
+
+    async fn set_expiry(&self, id: &str, expires_at: u64) -> Result<(), CipherLinkError> {
+        let owned_id = id.to_string();
+        let updated = self
+            .run(move |conn| {
+                conn.execute(
+                    "UPDATE records SET expires_at = ?2 WHERE id = ?1",
+                    params![owned_id, expires_at as i64],
+                )
+            })
+            .await?;
+        if updated == 0 {
+            return Err(CipherLinkError::not_found(id));
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>, CipherLinkError> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare("SELECT id FROM records")?;
+            let ids = stmt.query_map([], |row| row.get(0))?;
+            ids.collect()
+        })
+        .await
+    }
+
+    async fn health(&self) -> Result<(), CipherLinkError> {
+        self.run(|conn| conn.query_row("SELECT 1", [], |_| Ok(())))
+            .await
+    }
+}
+
+fn select(conn: &Connection, id: &str) -> rusqlite::Result<Option<Row>> {
+    conn.query_row(
+        &format!("SELECT {} FROM records WHERE id = ?1", COLUMNS),
+        params![id],
+        |row| {
+            Ok(Row {
+                envelope: row.get(0)?,
+                recipients: row.get(1)?,
+                client_ciphertext: row.get(2)?,
+                expires_at: row.get(3)?,
+                views_left: row.get(4)?,
+                max_attempts: row.get(5)?,
+                lockout: row.get(6)?,
+                failed_attempts: row.get(7)?,
+                locked_until: row.get(8)?,
+                management_hash: row.get(9)?,
+                kind: row.get(10)?,
+            })
+        },
+    )
+    .optional()
+}
+
+fn entry_to_row(entry: &Entry) -> Row {
+    let (envelope, recipients, client_ciphertext) = match &entry.record {
+        Record::Server(data) => {
+            let recipients =
+                (!data.recipients.is_empty()).then(|| recipients_to_bytes(&data.recipients));
+            (Some(envelope::to_bytes(data)), recipients, None)
+        }
+        Record::Client(ciphertext) => (None, None, Some(ciphertext.clone())),
+    };
+    let limit = entry.attempts.limit;
+    Row {
+        envelope,
+        recipients,
+        client_ciphertext,
+        expires_at: entry.expires_at.map(|t| t as i64),
+        views_left: entry.views_left,
+        max_attempts: limit.map(|l| l.max_attempts),
+        lockout: limit.map(|l| l.lockout.name().to_string()),
+        failed_attempts: entry.attempts.failed,
+        locked_until: entry.attempts.locked_until.map(|t| t as i64),
+        management_hash: entry.management_hash.clone(),
+        kind: Some(entry.kind.name().to_string()),
+    }
+}
+
+/// Reads the attempt columns. An unknown lockout name falls back to
+/// locking, the safer of the two.
+fn row_to_attempts(row: &Row) -> Attempts {
+    Attempts {
+        limit: row.max_attempts.map(|max_attempts| AttemptLimit {
+            max_attempts,
+            lockout: row
+                .lockout
+                .as_deref()
+                .and_then(Lockout::from_name)
+                .unwrap_or_default(),
+        }),
+        failed: row.failed_attempts,
+        locked_until: row.locked_until.map(|t| t as u64),
+    }
+}
+
+fn row_to_entry(row: Row) -> Result<Entry, CipherLinkError> {
+    let attempts = row_to_attempts(&row);
+    let kind = match row.kind.as_deref() {
+        None => SecretKind::default(),
+        Some(name) => SecretKind::from_name(name)
+            .ok_or_else(|| CipherLinkError::Corrupt(format!("Unknown 'kind' {}", name)))?,
+    };
+    let record = match (row.envelope, row.client_ciphertext) {
+        (_, Some(ciphertext)) => Record::Client(ciphertext),
+        (Some(bytes), None) => {
+            let mut data = envelope::from_bytes(&bytes)
+                .map_err(|e| CipherLinkError::Corrupt(e.to_string()))?;
+            if let Some(recipients) = row.recipients {
+                data.recipients = bytes_to_recipients(&recipients)?;
+            }
+            Record::Server(data)
+        }
+        (None, None) => return Err(CipherLinkError::Corrupt("Missing 'envelope'".into())),
+    };
+    Ok(Entry {
+        record,
+        kind,
+        expires_at: row.expires_at.map(|t| t as u64),
+        views_left: row.views_left,
+        attempts,
+        management_hash: row.management_hash,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{CryptoConfig, encrypt};
+
+    async fn test_store() -> SqliteStore {
+        let store = SqliteStore::open(":memory:").expect("open failed");
+        store.setup().await.expect("setup failed");
+        // Migrations only run once.
+        store.setup().await.expect("setup failed");
+        store
+    }
+
+    fn client_entry(ciphertext: u8) -> Entry {
+        Entry::new(Record::Client(vec![ciphertext]))
+    }
+
+    #[tokio::test]
+    async fn test_put_get_take() {
+        let store = test_store().await;
+        store.put("a", client_entry(1)).await.unwrap();
+        assert!(
+            matches!(store.get("a").await, Ok(Entry { record: Record::Client(c), .. }) if c == vec![1])
+        );
+        assert_eq!(vec!["a".to_string()], store.list().await.unwrap());
+
+        assert!(
+            matches!(store.take("a").await, Ok(Entry { record: Record::Client(c), .. }) if c == vec![1])
+        );
+        assert!(store.take("a").await.is_err());
+        assert!(store.get("a").await.is_err());
+        assert!(store.health().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_server_record() {
+        let store = test_store().await;
+        let data = encrypt("abc", "key", "b", &CryptoConfig::default()).unwrap();
+        let mut entry = Entry::new(Record::Server(data.clone()));
+        let expiry = now() + 60;
+        entry.expires_at = Some(expiry);
+        store.put("b", entry).await.unwrap();
+        let Ok(Entry {
+            record: Record::Server(got),
+            expires_at,
+            ..
+        }) = store.get("b").await
+        else {
+            panic!("expected a server record");
+        };
+        assert_eq!(data.encrypted_text, got.encrypted_text);
+        assert_eq!(data.commitment, got.commitment);
+        assert_eq!(Some(expiry), expires_at);
+    }
+
+    #[tokio::test]
+    async fn test_swap_envelope() {
+        let store = test_store().await;
+        let config = CryptoConfig::default();
+        let old = encrypt("abc", "key", "a", &config).unwrap();
+        let new = encrypt("abc", "key", "a", &config).unwrap();
+        let mut entry = Entry::new(Record::Server(old.clone()));
+        entry.views_left = 2;
+        store.put("a", entry).await.unwrap();
+        store.consume("a").await.unwrap();
+        store.fail_attempt("a", 0).await.unwrap();
+
+        store.swap_envelope("a", &old, &new).await.unwrap();
+        let entry = store.get("a").await.unwrap();
+        assert!(matches!(entry.record, Record::Server(got) if got.salt == new.salt));
+        assert_eq!(1, entry.views_left);
+        assert_eq!(1, entry.attempts.failed);
+        assert!(store.swap_envelope("a", &old, &new).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_consume() {
+        let store = test_store().await;
+        let mut entry = client_entry(1);
+        entry.views_left = 2;
+        store.put("a", entry).await.unwrap();
+        assert_eq!(Ok(1), store.consume("a").await);
+        assert_eq!(1, store.get("a").await.unwrap().views_left);
+        assert_eq!(Ok(0), store.consume("a").await);
+        assert!(store.consume("a").await.is_err());
+        assert!(store.get("a").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fail_attempt() {
+        let store = test_store().await;
+        let mut entry = client_entry(1);
+        entry.attempts.limit = Some(AttemptLimit {
+            max_attempts: 2,
+            lockout: Lockout::Destroy,
+        });
+        store.put("a", entry).await.unwrap();
+        assert_eq!(
+            Ok(AttemptOutcome::Counted),
+            store.fail_attempt("a", 0).await
+        );
+        assert_eq!(1, store.get("a").await.unwrap().attempts.failed);
+        assert_eq!(
+            Ok(AttemptOutcome::Destroyed),
+            store.fail_attempt("a", 0).await
+        );
+        assert!(store.get("a").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_put_purges_expired() {
+        let store = test_store().await;
+        let mut expired = client_entry(1);
+        expired.expires_at = Some(now() - 1);
+        store.put("a", expired).await.unwrap();
+        assert!(store.get("a").await.unwrap().is_expired(now()));
+
+        store.put("b", client_entry(2)).await.unwrap();
+        assert_eq!(vec!["b".to_string()], store.list().await.unwrap());
+    }
+}
@@ -2,58 +2,421 @@ use std::collections::HashMap;
 
 use aws_sdk_dynamodb::types::AttributeValue;
 
-use crate::crypto::EncryptData;
+use crate::{
+    crypto::{
+        CipherSuite, EncryptData, Kdf, KdfParams, Padding,
+        envelope::{self, LEGACY_VERSION},
+        recipient::{PUBLIC_KEY_LEN, RecipientStanza},
+    },
+    error::CipherLinkError,
+    store::{AttemptLimit, Attempts, Entry, Lockout, Record, SecretKind},
+};
 
-/// encodes an EncryptData struct into binary to be stored in
-/// dynamodb so the data doesn't get mangled.
+const KDF_SHA256: &str = "sha256";
+const KDF_ARGON2ID: &str = "argon2id";
+
+/// Name of the binary envelope attribute of server-side records.
+pub const ENVELOPE_ATTRIBUTE: &str = "envelope";
+
+/// Name of the numeric expiry attribute, in unix seconds. The table's
+/// TTL is enabled on it.
+pub const EXPIRES_AT_ATTRIBUTE: &str = "expires_at";
+
+/// Name of the numeric views left attribute. Items without it are
+/// one-time.
+pub const VIEWS_LEFT_ATTRIBUTE: &str = "views_left";
+
+/// Names of the attempt limit attributes. Items without a limit are
+/// from before attempts were limited.
+pub const MAX_ATTEMPTS_ATTRIBUTE: &str = "max_attempts";
+pub const LOCKOUT_ATTRIBUTE: &str = "lockout";
+pub const FAILED_ATTEMPTS_ATTRIBUTE: &str = "failed_attempts";
+pub const LOCKED_UNTIL_ATTRIBUTE: &str = "locked_until";
+
+/// Name of the secret kind attribute. Items without it are redirects.
+pub const KIND_ATTRIBUTE: &str = "kind";
+
+/// Name of the binary management token hash attribute.
+pub const MANAGEMENT_HASH_ATTRIBUTE: &str = "management_hash";
+
+/// encodes a store entry into a dynamodb item.
+pub fn entry_to_item(id: &str, entry: &Entry) -> HashMap<String, AttributeValue> {
+    let mut item = match &entry.record {
+        Record::Server(data) => encrypt_data_to_item(id, data),
+        Record::Client(ciphertext) => client_ciphertext_to_item(id, ciphertext.clone()),
+    };
+    item.insert(
+        KIND_ATTRIBUTE.to_string(),
+        AttributeValue::S(entry.kind.name().to_string()),
+    );
+    if let Some(expires_at) = entry.expires_at {
+        item.insert(
+            EXPIRES_AT_ATTRIBUTE.to_string(),
+            AttributeValue::N(expires_at.to_string()),
+        );
+    }
+    item.insert(
+        VIEWS_LEFT_ATTRIBUTE.to_string(),
+        AttributeValue::N(entry.views_left.to_string()),
+    );
+    item.insert(
+        FAILED_ATTEMPTS_ATTRIBUTE.to_string(),
+        AttributeValue::N(entry.attempts.failed.to_string()),
+    );
+    if let Some(limit) = entry.attempts.limit {
+        item.insert(
+            MAX_ATTEMPTS_ATTRIBUTE.to_string(),
+            AttributeValue::N(limit.max_attempts.to_string()),
+        );
+        item.insert(
+            LOCKOUT_ATTRIBUTE.to_string(),
+            AttributeValue::S(limit.lockout.name().to_string()),
+        );
+    }
+    if let Some(locked_until) = entry.attempts.locked_until {
+        item.insert(
+            LOCKED_UNTIL_ATTRIBUTE.to_string(),
+            AttributeValue::N(locked_until.to_string()),
+        );
+    }
+    if let Some(management_hash) = &entry.management_hash {
+        item.insert(
+            MANAGEMENT_HASH_ATTRIBUTE.to_string(),
+            AttributeValue::B(management_hash.clone().into()),
+        );
+    }
+    item
+}
+
+/// decodes a dynamodb item into a store entry.
+///
+/// # Errors
+/// Can error if the item is missing expected attributes or the
+/// envelope can't be parsed.
+pub fn item_to_entry(item: &HashMap<String, AttributeValue>) -> Result<Entry, CipherLinkError> {
+    let record = match item_to_client_ciphertext(item)? {
+        Some(ciphertext) => Record::Client(ciphertext),
+        None => Record::Server(item_to_encryt_data(item)?),
+    };
+    let views_left = match item.get(VIEWS_LEFT_ATTRIBUTE) {
+        None => 1,
+        Some(_) => get_u32(item, VIEWS_LEFT_ATTRIBUTE)?,
+    };
+    let kind = match item.get(KIND_ATTRIBUTE) {
+        None => SecretKind::default(),
+        Some(AttributeValue::S(name)) => SecretKind::from_name(name).ok_or_else(|| {
+            CipherLinkError::Corrupt(format!("Unknown '{}' {}", KIND_ATTRIBUTE, name))
+        })?,
+        Some(_) => {
+            return Err(CipherLinkError::Corrupt(format!(
+                "Invalid '{}'",
+                KIND_ATTRIBUTE
+            )));
+        }
+    };
+    Ok(Entry {
+        record,
+        kind,
+        expires_at: get_optional_u64(item, EXPIRES_AT_ATTRIBUTE)?,
+        views_left,
+        attempts: item_to_attempts(item)?,
+        management_hash: match item.get(MANAGEMENT_HASH_ATTRIBUTE) {
+            Some(AttributeValue::B(bytes)) => Some(bytes.as_ref().to_vec()),
+            Some(_) => {
+                return Err(CipherLinkError::Corrupt(format!(
+                    "Invalid '{}'",
+                    MANAGEMENT_HASH_ATTRIBUTE
+                )));
+            }
+            None => None,
+        },
+    })
+}
+
+/// decodes the attempt limit attributes of an item. An unknown
+/// lockout falls back to locking, the safer of the two.
+///
+/// # Errors
+/// Can error if an attribute has the wrong type.
+pub fn item_to_attempts(
+    item: &HashMap<String, AttributeValue>,
+) -> Result<Attempts, CipherLinkError> {
+    let limit = match item.get(MAX_ATTEMPTS_ATTRIBUTE) {
+        None => None,
+        Some(_) => Some(AttemptLimit {
+            max_attempts: get_u32(item, MAX_ATTEMPTS_ATTRIBUTE)?,
+            lockout: match item.get(LOCKOUT_ATTRIBUTE) {
+                Some(AttributeValue::S(name)) => Lockout::from_name(name).unwrap_or_default(),
+                _ => Lockout::default(),
+            },
+        }),
+    };
+    let failed = match item.get(FAILED_ATTEMPTS_ATTRIBUTE) {
+        None => 0,
+        Some(_) => get_u32(item, FAILED_ATTEMPTS_ATTRIBUTE)?,
+    };
+    Ok(Attempts {
+        limit,
+        failed,
+        locked_until: get_optional_u64(item, LOCKED_UNTIL_ATTRIBUTE)?,
+    })
+}
+
+/// encodes an EncryptData struct into a binary envelope to be stored
+/// in dynamodb so the data doesn't get mangled. Recipient stanzas are
+/// stored as a list of ephemeral public key || wrapped file key.
 pub fn encrypt_data_to_item(id: &str, data: &EncryptData) -> HashMap<String, AttributeValue> {
     let mut item = HashMap::new();
     item.insert("id".to_string(), AttributeValue::S(id.to_string()));
     item.insert(
-        "nonce".to_string(),
-        AttributeValue::B(data.nonce.clone().into()),
+        ENVELOPE_ATTRIBUTE.to_string(),
+        AttributeValue::B(envelope::to_bytes(data).into()),
     );
+    if !data.recipients.is_empty() {
+        let stanzas = data
+            .recipients
+            .iter()
+            .map(|stanza| {
+                let mut bytes = stanza.ephemeral_public.to_vec();
+                bytes.extend_from_slice(&stanza.wrapped_key);
+                AttributeValue::B(bytes.into())
+            })
+            .collect();
+        item.insert("recipients".to_string(), AttributeValue::L(stanzas));
+    }
+    item
+}
+
+/// stores a client-side encrypted envelope as is. The server can't
+/// decrypt it, so it is kept apart from server-side envelopes.
+pub fn client_ciphertext_to_item(id: &str, ciphertext: Vec<u8>) -> HashMap<String, AttributeValue> {
+    let mut item = HashMap::new();
+    item.insert("id".to_string(), AttributeValue::S(id.to_string()));
     item.insert(
-        "cipher_text".to_string(),
-        AttributeValue::B(data.encrypted_text.clone().into()),
+        "client_ciphertext".to_string(),
+        AttributeValue::B(ciphertext.into()),
     );
     item
 }
 
+/// returns the client-side envelope of an item, or None if the item
+/// was encrypted server-side.
+///
+/// # Errors
+/// Can error if the attribute is not binary.
+pub fn item_to_client_ciphertext(
+    item: &HashMap<String, AttributeValue>,
+) -> Result<Option<Vec<u8>>, CipherLinkError> {
+    match item.get("client_ciphertext") {
+        Some(AttributeValue::B(bytes)) => Ok(Some(bytes.as_ref().to_vec())),
+        Some(_) => Err(CipherLinkError::Corrupt(
+            "Invalid 'client_ciphertext'".into(),
+        )),
+        None => Ok(None),
+    }
+}
+
 /// decodes data retrieved from dynamodb to an EncryptData struct.
+/// Items written before the envelope existed store the nonce, cipher
+/// text and KDF as separate attributes and are still accepted.
+///
+/// # Errors
+/// Can error if the item is missing expected attributes or the
+/// envelope can't be parsed.
+pub fn item_to_encryt_data(
+    item: &HashMap<String, AttributeValue>,
+) -> Result<EncryptData, CipherLinkError> {
+    if item.contains_key("client_ciphertext") {
+        return Err(CipherLinkError::Corrupt(
+            "Item was encrypted client-side".into(),
+        ));
+    }
+    let mut data = match item.get(ENVELOPE_ATTRIBUTE) {
+        Some(AttributeValue::B(bytes)) => envelope::from_bytes(bytes.as_ref())
+            .map_err(|e| CipherLinkError::Corrupt(e.to_string()))?,
+        Some(_) => return Err(CipherLinkError::Corrupt("Invalid 'envelope'".into())),
+        None => return legacy_item_to_encrypt_data(item),
+    };
+    data.recipients = item_to_recipients(item)?;
+    Ok(data)
+}
+
+/// encodes recipient stanzas as one byte string for stores without a
+/// list type: per stanza a length byte, then ephemeral public key ||
+/// wrapped file key.
+pub fn recipients_to_bytes(stanzas: &[RecipientStanza]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for stanza in stanzas {
+        // wrapped keys are a sealed 32-byte key and always fit.
+        bytes.push((PUBLIC_KEY_LEN + stanza.wrapped_key.len()) as u8);
+        bytes.extend_from_slice(&stanza.ephemeral_public);
+        bytes.extend_from_slice(&stanza.wrapped_key);
+    }
+    bytes
+}
+
+/// decodes stanzas written by recipients_to_bytes.
 ///
 /// # Errors
-/// Can error if the item is missing expected attributes.
-pub fn item_to_encryt_data(item: &HashMap<String, AttributeValue>) -> Result<EncryptData, String> {
+/// Can error if the bytes are truncated.
+pub fn bytes_to_recipients(mut bytes: &[u8]) -> Result<Vec<RecipientStanza>, CipherLinkError> {
+    let mut stanzas = Vec::new();
+    while let Some((&len, rest)) = bytes.split_first() {
+        let len = len as usize;
+        if len <= PUBLIC_KEY_LEN || rest.len() < len {
+            return Err(CipherLinkError::Corrupt("Invalid 'recipients'".into()));
+        }
+        let (stanza, rest) = rest.split_at(len);
+        stanzas.push(stanza_from_bytes(stanza));
+        bytes = rest;
+    }
+    Ok(stanzas)
+}
+
+/// splits ephemeral public key || wrapped file key, the caller checks
+/// the length.
+fn stanza_from_bytes(bytes: &[u8]) -> RecipientStanza {
+    let (ephemeral_public, wrapped_key) = bytes.split_at(PUBLIC_KEY_LEN);
+    RecipientStanza {
+        ephemeral_public: ephemeral_public.try_into().expect("split at key length"),
+        wrapped_key: wrapped_key.to_vec(),
+    }
+}
+
+/// decodes the recipient stanzas of an item, if it has any.
+fn item_to_recipients(
+    item: &HashMap<String, AttributeValue>,
+) -> Result<Vec<RecipientStanza>, CipherLinkError> {
+    let stanzas = match item.get("recipients") {
+        Some(AttributeValue::L(stanzas)) => stanzas,
+        Some(_) => return Err(CipherLinkError::Corrupt("Invalid 'recipients'".into())),
+        None => return Ok(Vec::new()),
+    };
+    stanzas
+        .iter()
+        .map(|stanza| match stanza {
+            AttributeValue::B(bytes) if bytes.as_ref().len() > PUBLIC_KEY_LEN => {
+                Ok(stanza_from_bytes(bytes.as_ref()))
+            }
+            _ => Err(CipherLinkError::Corrupt("Invalid 'recipients'".into())),
+        })
+        .collect()
+}
+
+/// decodes a pre-envelope item. Items written before the KDF was
+/// recorded have no 'kdf' attribute and are treated as unsalted SHA-256.
+fn legacy_item_to_encrypt_data(
+    item: &HashMap<String, AttributeValue>,
+) -> Result<EncryptData, CipherLinkError> {
     let nonce = match item.get("nonce") {
         Some(AttributeValue::B(bytes)) => bytes.as_ref().to_vec(),
-        _ => return Err("Missing or invalid 'nonce'".into()),
+        _ => {
+            return Err(CipherLinkError::Corrupt(
+                "Missing or invalid 'nonce'".into(),
+            ));
+        }
     };
     let cipher_text = match item.get("cipher_text") {
         Some(AttributeValue::B(bytes)) => bytes.as_ref().to_vec(),
-        _ => return Err("Missing or invalid 'cipher_text'".into()),
+        _ => {
+            return Err(CipherLinkError::Corrupt(
+                "Missing or invalid 'cipher_text'".into(),
+            ));
+        }
+    };
+    let kdf = match item.get("kdf") {
+        None => Kdf::Sha256,
+        Some(AttributeValue::S(kdf)) if kdf == KDF_SHA256 => Kdf::Sha256,
+        Some(AttributeValue::S(kdf)) if kdf == KDF_ARGON2ID => Kdf::Argon2id(
+            KdfParams {
+                m_cost: get_u32(item, "kdf_m_cost")?,
+                t_cost: get_u32(item, "kdf_t_cost")?,
+                p_cost: get_u32(item, "kdf_p_cost")?,
+            }
+            .check()
+            .map_err(|e| CipherLinkError::Corrupt(e.to_string()))?,
+        ),
+        _ => return Err(CipherLinkError::Corrupt("Missing or invalid 'kdf'".into())),
+    };
+    let salt = match item.get("salt") {
+        Some(AttributeValue::B(bytes)) => bytes.as_ref().to_vec(),
+        None if kdf == Kdf::Sha256 => Vec::new(),
+        _ => return Err(CipherLinkError::Corrupt("Missing or invalid 'salt'".into())),
     };
     Ok(EncryptData {
+        version: LEGACY_VERSION,
+        suite: CipherSuite::Aes256Gcm,
+        kdf,
+        salt,
         nonce,
+        commitment: Vec::new(),
+        padding: Padding::None,
+        wrapped_key: None,
+        recipients: Vec::new(),
         encrypted_text: cipher_text,
     })
 }
 
+/// reads an optional numeric attribute that must fit in a u64.
+fn get_optional_u64(
+    item: &HashMap<String, AttributeValue>,
+    name: &str,
+) -> Result<Option<u64>, CipherLinkError> {
+    match item.get(name) {
+        Some(AttributeValue::N(n)) => n
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|_| CipherLinkError::Corrupt(format!("Invalid '{}'", name))),
+        Some(_) => Err(CipherLinkError::Corrupt(format!("Invalid '{}'", name))),
+        None => Ok(None),
+    }
+}
+
+/// reads a numeric attribute that must fit in a u32.
+fn get_u32(item: &HashMap<String, AttributeValue>, name: &str) -> Result<u32, CipherLinkError> {
+    match item.get(name) {
+        Some(AttributeValue::N(n)) => n
+            .parse::<u32>()
+            .map_err(|_| CipherLinkError::Corrupt(format!("Invalid '{}'", name))),
+        _ => Err(CipherLinkError::Corrupt(format!(
+            "Missing or invalid '{}'",
+            name
+        ))),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypto::envelope::ENVELOPE_VERSION;
+
+    fn test_data() -> EncryptData {
+        EncryptData {
+            version: ENVELOPE_VERSION,
+            suite: CipherSuite::Aes256Gcm,
+            kdf: Kdf::Argon2id(KdfParams {
+                m_cost: 64,
+                t_cost: 1,
+                p_cost: 1,
+            }),
+            salt: vec![0x01, 0x02, 0x03],
+            nonce: vec![0x04, 0x05, 0x06],
+            commitment: vec![0x0c, 0x0d],
+            padding: Padding::Buckets,
+            wrapped_key: None,
+            recipients: Vec::new(),
+            encrypted_text: vec![0x07, 0x08, 0x09],
+        }
+    }
 
     #[test]
     fn test_encrypt_data_to_item() {
         // Not really a meaningful test, just wanted to have a test module
         // for when I do add more tests.
         let id = "5";
-        let data = &EncryptData {
-            nonce: vec![0x04, 0x05, 0x06],
-            encrypted_text: vec![0x07, 0x08, 0x09],
-        };
+        let data = &test_data();
         let got = encrypt_data_to_item(id, data);
-        let expected_len = 4;
+        let expected_len = 2;
         assert_eq!(
             expected_len,
             got.len(),
@@ -66,10 +429,7 @@ mod tests {
     #[test]
     fn test_item_to_encrypt_data() {
         let id = "5";
-        let data = &EncryptData {
-            nonce: vec![0x04, 0x05, 0x06],
-            encrypted_text: vec![0x07, 0x08, 0x09],
-        };
+        let data = &test_data();
         let item = encrypt_data_to_item(id, data);
         let got = item_to_encryt_data(&item).expect("failed to transform");
         assert_eq!(
@@ -81,6 +441,109 @@ mod tests {
             data.encrypted_text, got.encrypted_text,
             "expected cipher_text: {:?}, got: {:?}",
             data.encrypted_text, got.encrypted_text,
-        )
+        );
+        assert_eq!(data.salt, got.salt);
+        assert_eq!(data.kdf, got.kdf);
+        assert_eq!(ENVELOPE_VERSION, got.version);
+    }
+
+    #[test]
+    fn test_recipients_item() {
+        let mut data = test_data();
+        data.kdf = Kdf::X25519;
+        data.recipients = vec![RecipientStanza {
+            ephemeral_public: [0x01; PUBLIC_KEY_LEN],
+            wrapped_key: vec![0x02, 0x03],
+        }];
+        let item = encrypt_data_to_item("5", &data);
+        assert_eq!(3, item.len());
+        let got = item_to_encryt_data(&item).expect("failed to transform");
+        assert_eq!(data.recipients, got.recipients);
+
+        let bytes = recipients_to_bytes(&data.recipients);
+        assert_eq!(data.recipients, bytes_to_recipients(&bytes).unwrap());
+        assert!(bytes_to_recipients(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_client_ciphertext_item() {
+        let item = client_ciphertext_to_item("5", vec![0x01, 0x02]);
+        assert_eq!(
+            Some(vec![0x01, 0x02]),
+            item_to_client_ciphertext(&item).expect("failed to transform")
+        );
+        assert!(item_to_encryt_data(&item).is_err());
+
+        let item = encrypt_data_to_item("5", &test_data());
+        assert_eq!(
+            None,
+            item_to_client_ciphertext(&item).expect("failed to transform")
+        );
+    }
+
+    #[test]
+    fn test_entry_item() {
+        let item = entry_to_item("5", &Entry::new(Record::Client(vec![0x01])));
+        assert!(
+            matches!(item_to_entry(&item), Ok(Entry { record: Record::Client(c), expires_at: None, views_left: 1, .. }) if c == vec![0x01])
+        );
+
+        let mut entry = Entry::new(Record::Server(test_data()));
+        entry.expires_at = Some(1_700_000_000);
+        entry.views_left = 3;
+        entry.attempts = Attempts {
+            limit: Some(AttemptLimit {
+                max_attempts: 5,
+                lockout: Lockout::Destroy,
+            }),
+            failed: 2,
+            locked_until: Some(1_700_000_100),
+        };
+        entry.management_hash = Some(vec![0x0e; 32]);
+        let item = entry_to_item("5", &entry);
+        assert_eq!(
+            Some(&AttributeValue::N("1700000000".into())),
+            item.get(EXPIRES_AT_ATTRIBUTE)
+        );
+        let got = item_to_entry(&item).expect("failed to transform");
+        assert!(matches!(got.record, Record::Server(_)));
+        assert_eq!(Some(1_700_000_000), got.expires_at);
+        assert_eq!(3, got.views_left);
+        assert_eq!(entry.attempts, got.attempts);
+        assert_eq!(entry.management_hash, got.management_hash);
+    }
+
+    #[test]
+    fn test_legacy_item_to_encrypt_data() {
+        let data = test_data();
+        let mut item = HashMap::new();
+        item.insert("id".to_string(), AttributeValue::S("5".into()));
+        item.insert(
+            "nonce".to_string(),
+            AttributeValue::B(data.nonce.clone().into()),
+        );
+        item.insert(
+            "cipher_text".to_string(),
+            AttributeValue::B(data.encrypted_text.clone().into()),
+        );
+        let got = item_to_encryt_data(&item).expect("failed to transform");
+        assert_eq!(LEGACY_VERSION, got.version);
+        assert_eq!(Kdf::Sha256, got.kdf);
+        assert!(got.salt.is_empty());
+
+        item.insert("kdf".to_string(), AttributeValue::S(KDF_ARGON2ID.into()));
+        item.insert("salt".to_string(), AttributeValue::B(data.salt.into()));
+        for name in ["kdf_m_cost", "kdf_t_cost", "kdf_p_cost"] {
+            item.insert(name.to_string(), AttributeValue::N("1".into()));
+        }
+        let got = item_to_encryt_data(&item).expect("failed to transform");
+        assert_eq!(
+            Kdf::Argon2id(KdfParams {
+                m_cost: 1,
+                t_cost: 1,
+                p_cost: 1,
+            }),
+            got.kdf
+        );
     }
 }
@@ -1,30 +1,446 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+use crate::store::{Lockout, SecretKind};
+
+/// A secret string such as a link key or plaintext URL. The contents
+/// are wiped from memory on drop and never printed by `Debug`.
+#[derive(Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl SecretString {
+    /// Borrows the secret. Avoid copying it into plain `String`s.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        SecretString(secret)
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString(<redacted>)")
+    }
+}
 
 #[derive(Serialize)]
 pub struct HealthStatus {
     pub status: &'static str,
 }
 
-#[derive(Deserialize)]
+/// When a link stops working, either `ttl_seconds` from now or at the
+/// unix time `expires_at`. Links without either never expire.
+#[derive(Default, Deserialize)]
+pub struct Expiry {
+    pub ttl_seconds: Option<u64>,
+    pub expires_at: Option<u64>,
+}
+
 pub struct EncryptRequest {
-    pub plain_text: String,
-    pub key: String,
+    pub plain_text: SecretString,
+    pub key: SecretString,
+    /// What the plain text is, a URL to redirect to if not set.
+    pub kind: Option<SecretKind>,
+    /// How many times the link can be opened, 1 if not set.
+    pub max_views: Option<u32>,
+    /// Failed attempts before the link is locked or destroyed.
+    pub max_attempts: Option<u32>,
+    /// What happens after the last failed attempt, locking if not set.
+    pub on_max_attempts: Option<Lockout>,
+    pub expiry: Expiry,
+}
+
+/// Zero-knowledge mode: the client already encrypted the URL and sends
+/// the base64 encoded envelope. The server never sees the key.
+pub struct ClientEncryptRequest {
+    pub ciphertext: String,
+    pub expiry: Expiry,
+}
+
+/// Encrypts to recipients instead of a shared key. Each recipient is a
+/// base64 encoded X25519 public key.
+pub struct RecipientEncryptRequest {
+    pub plain_text: SecretString,
+    pub recipients: Vec<String>,
+    pub kind: Option<SecretKind>,
+    pub max_views: Option<u32>,
+    pub max_attempts: Option<u32>,
+    pub on_max_attempts: Option<Lockout>,
+    pub expiry: Expiry,
+}
+
+/// Encrypts under a key split into `shares` Shamir shares, any
+/// `threshold` of which decrypt.
+pub struct SplitEncryptRequest {
+    pub plain_text: SecretString,
+    pub threshold: u8,
+    pub shares: u8,
+    pub kind: Option<SecretKind>,
+    pub max_views: Option<u32>,
+    pub max_attempts: Option<u32>,
+    pub on_max_attempts: Option<Lockout>,
+    pub expiry: Expiry,
+}
+
+/// The fields of a /files upload besides the file itself.
+pub struct FileUploadRequest {
+    pub key: SecretString,
+    pub max_attempts: Option<u32>,
+    pub on_max_attempts: Option<Lockout>,
+    pub expiry: Expiry,
+}
+
+/// Body accepted by /encrypt, either server-side encryption with a
+/// key, to recipients or split into shares, or client-side encryption.
+/// Which one is told by the fields sent, see `EncryptBody`.
+#[derive(Deserialize)]
+#[serde(try_from = "EncryptBody")]
+pub enum EncryptPayload {
+    Server(EncryptRequest),
+    Recipients(RecipientEncryptRequest),
+    Split(SplitEncryptRequest),
+    Client(ClientEncryptRequest),
+}
+
+/// Every field /encrypt takes. The unlock method is whichever of `key`,
+/// `recipients`, `threshold` and `shares`, or `ciphertext` is sent.
+/// Sending several is refused rather than one of them quietly winning.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EncryptBody {
+    plain_text: Option<SecretString>,
+    key: Option<SecretString>,
+    recipients: Option<Vec<String>>,
+    threshold: Option<u8>,
+    shares: Option<u8>,
+    ciphertext: Option<String>,
+    kind: Option<SecretKind>,
+    max_views: Option<u32>,
+    max_attempts: Option<u32>,
+    on_max_attempts: Option<Lockout>,
+    ttl_seconds: Option<u64>,
+    expires_at: Option<u64>,
+}
+
+impl TryFrom<EncryptBody> for EncryptPayload {
+    type Error = String;
+
+    fn try_from(body: EncryptBody) -> Result<Self, String> {
+        let methods = [
+            ("key", body.key.is_some()),
+            ("recipients", body.recipients.is_some()),
+            (
+                "threshold/shares",
+                body.threshold.is_some() || body.shares.is_some(),
+            ),
+            ("ciphertext", body.ciphertext.is_some()),
+        ];
+        let sent: Vec<&str> = methods
+            .iter()
+            .filter(|(_, sent)| *sent)
+            .map(|(name, _)| *name)
+            .collect();
+        match sent.len() {
+            0 => return Err("Missing key, recipients, threshold and shares, or ciphertext".into()),
+            1 => {}
+            _ => {
+                return Err(format!(
+                    "Only one unlock method can be sent, got {}",
+                    sent.join(", ")
+                ));
+            }
+        }
+        let expiry = Expiry {
+            ttl_seconds: body.ttl_seconds,
+            expires_at: body.expires_at,
+        };
+
+        if let Some(ciphertext) = body.ciphertext {
+            if body.plain_text.is_some() {
+                return Err("plain_text can't be sent with ciphertext".into());
+            }
+            if body.kind.is_some()
+                || body.max_views.is_some()
+                || body.max_attempts.is_some()
+                || body.on_max_attempts.is_some()
+            {
+                return Err(
+                    "kind, max_views, max_attempts and on_max_attempts don't apply to ciphertext"
+                        .into(),
+                );
+            }
+            return Ok(EncryptPayload::Client(ClientEncryptRequest {
+                ciphertext,
+                expiry,
+            }));
+        }
+
+        let plain_text = body.plain_text.ok_or("Missing plain_text")?;
+        if let Some(key) = body.key {
+            return Ok(EncryptPayload::Server(EncryptRequest {
+                plain_text,
+                key,
+                kind: body.kind,
+                max_views: body.max_views,
+                max_attempts: body.max_attempts,
+                on_max_attempts: body.on_max_attempts,
+                expiry,
+            }));
+        }
+        if let Some(recipients) = body.recipients {
+            return Ok(EncryptPayload::Recipients(RecipientEncryptRequest {
+                plain_text,
+                recipients,
+                kind: body.kind,
+                max_views: body.max_views,
+                max_attempts: body.max_attempts,
+                on_max_attempts: body.on_max_attempts,
+                expiry,
+            }));
+        }
+        let (Some(threshold), Some(shares)) = (body.threshold, body.shares) else {
+            return Err("threshold and shares must be sent together".into());
+        };
+        Ok(EncryptPayload::Split(SplitEncryptRequest {
+            plain_text,
+            threshold,
+            shares,
+            kind: body.kind,
+            max_views: body.max_views,
+            max_attempts: body.max_attempts,
+            on_max_attempts: body.on_max_attempts,
+            expiry,
+        }))
+    }
 }
 
 #[derive(Serialize)]
 pub struct EncryptResponse {
     pub id: String,
+    /// The base64 encoded Shamir shares of a split record.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shares: Option<Vec<SecretString>>,
+    /// Unix time in seconds the link expires at, if it does.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Lets the creator inspect, revoke or re-expire the link. It is
+    /// only stored hashed and can't be recovered.
+    pub management_token: SecretString,
 }
 
 #[derive(Serialize)]
 #[serde(tag = "status", content = "data")]
 pub enum EncryptApiResponse {
     Ok(EncryptResponse),
-    Err(String),
+}
+
+/// The body of every error response. `code` is stable, `error` is
+/// meant for people.
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub error: String,
+    pub code: &'static str,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct DecryptParams {
     pub id: String,
-    pub key: String,
+    pub key: SecretString,
+}
+
+/// Response header telling how many more times a link can be opened.
+pub const VIEWS_LEFT_HEADER: &str = "x-views-left";
+
+/// A decrypted link, how to hand it out and how many more times it can
+/// be opened, 0 once it is gone.
+#[derive(Debug)]
+pub struct DecryptResponse {
+    pub plain_text: SecretString,
+    pub kind: SecretKind,
+    pub views_left: u32,
+}
+
+/// The body a decrypted `json` secret is returned in.
+#[derive(Serialize)]
+pub struct SecretResponse {
+    pub secret: serde_json::Value,
+    pub views_left: u32,
+}
+
+/// Request header carrying a link's management token.
+pub const MANAGEMENT_TOKEN_HEADER: &str = "x-management-token";
+
+/// What a link's owner sees of it: `active`, `locked`, `expired`, or
+/// `gone` once it was consumed or revoked.
+#[derive(Debug, Serialize)]
+pub struct LinkStatus {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub views_left: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locked_until: Option<u64>,
+}
+
+impl LinkStatus {
+    /// The status of a link that no longer exists.
+    pub fn gone() -> Self {
+        LinkStatus {
+            status: "gone",
+            views_left: None,
+            expires_at: None,
+            locked_until: None,
+        }
+    }
+}
+
+/// The key a record was encrypted with, sent in a body instead of the
+/// URL path.
+#[derive(Debug)]
+pub struct KeyDecryptRequest {
+    pub key: SecretString,
+}
+
+/// One recipient's base64 encoded X25519 private key.
+#[derive(Debug)]
+pub struct RecipientDecryptRequest {
+    pub identity: SecretString,
+}
+
+/// At least the threshold of a split record's base64 encoded shares.
+#[derive(Debug)]
+pub struct ShareDecryptRequest {
+    pub shares: Vec<SecretString>,
+}
+
+/// Body accepted by POST /decrypt/{id}, a key or what unlocks records
+/// that were not encrypted with one.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "DecryptBody")]
+pub enum DecryptPayload {
+    Key(KeyDecryptRequest),
+    Recipient(RecipientDecryptRequest),
+    Shares(ShareDecryptRequest),
+}
+
+/// Every field POST /decrypt/{id} takes, exactly one of which is sent.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DecryptBody {
+    key: Option<SecretString>,
+    identity: Option<SecretString>,
+    shares: Option<Vec<SecretString>>,
+}
+
+impl TryFrom<DecryptBody> for DecryptPayload {
+    type Error = String;
+
+    fn try_from(body: DecryptBody) -> Result<Self, String> {
+        match (body.key, body.identity, body.shares) {
+            (Some(key), None, None) => Ok(DecryptPayload::Key(KeyDecryptRequest { key })),
+            (None, Some(identity), None) => {
+                Ok(DecryptPayload::Recipient(RecipientDecryptRequest {
+                    identity,
+                }))
+            }
+            (None, None, Some(shares)) => {
+                Ok(DecryptPayload::Shares(ShareDecryptRequest { shares }))
+            }
+            (None, None, None) => Err("Missing key, identity or shares".into()),
+            _ => Err("Only one of key, identity and shares can be sent".into()),
+        }
+    }
+}
+
+/// The opaque client-side envelope, base64 encoded.
+#[derive(Serialize)]
+pub struct RetrieveResponse {
+    pub ciphertext: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_string_debug_is_redacted() {
+        let params: DecryptParams =
+            serde_json::from_str(r#"{"id":"5","key":"hunter2"}"#).expect("invalid json");
+        assert_eq!("hunter2", params.key.expose());
+        let debug = format!("{:?}", params);
+        assert!(!debug.contains("hunter2"), "key leaked: {}", debug);
+    }
+
+    #[test]
+    fn test_encrypt_payload_expiry() {
+        let payload: EncryptPayload =
+            serde_json::from_str(r#"{"plain_text":"a","key":"b","ttl_seconds":60}"#)
+                .expect("invalid json");
+        let EncryptPayload::Server(request) = payload else {
+            panic!("expected a server request");
+        };
+        assert_eq!(Some(60), request.expiry.ttl_seconds);
+        assert_eq!(None, request.expiry.expires_at);
+
+        let payload: EncryptPayload =
+            serde_json::from_str(r#"{"ciphertext":"AQ==","expires_at":1700000000}"#)
+                .expect("invalid json");
+        let EncryptPayload::Client(request) = payload else {
+            panic!("expected a client request");
+        };
+        assert_eq!(Some(1_700_000_000), request.expiry.expires_at);
+    }
+
+    #[test]
+    fn test_encrypt_payload_mixed_methods() {
+        let error = |body: &str| match serde_json::from_str::<EncryptPayload>(body) {
+            Ok(_) => panic!("accepted {}", body),
+            Err(e) => e.to_string(),
+        };
+        assert!(
+            error(r#"{"plain_text":"a","key":"b","recipients":["c"]}"#)
+                .contains("got key, recipients")
+        );
+        assert!(
+            error(r#"{"plain_text":"a","key":"b","threshold":2,"shares":3}"#)
+                .contains("got key, threshold/shares")
+        );
+        assert!(
+            error(r#"{"plain_text":"a","ciphertext":"AQ=="}"#)
+                .contains("plain_text can't be sent with ciphertext")
+        );
+        assert!(error(r#"{"plain_text":"a","threshold":2}"#).contains("sent together"));
+        assert!(error(r#"{"plain_text":"a"}"#).contains("Missing key"));
+        assert!(error(r#"{"plain_text":"a","key":"b","max_view":2}"#).contains("unknown field"));
+    }
+
+    #[test]
+    fn test_decrypt_payload_mixed_methods() {
+        let payload: DecryptPayload =
+            serde_json::from_str(r#"{"shares":["a","b"]}"#).expect("invalid json");
+        assert!(matches!(payload, DecryptPayload::Shares(_)));
+        let error = serde_json::from_str::<DecryptPayload>(r#"{"key":"a","identity":"b"}"#)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("Only one of key, identity and shares"),
+            "{}",
+            error
+        );
+        assert!(serde_json::from_str::<DecryptPayload>("{}").is_err());
+    }
 }
@@ -10,7 +10,8 @@ echo "▶️ Lambda /health test"
 cat > lambda_event.json <<EOF
 {
   "httpMethod": "GET",
-  "path": "/health"
+  "path": "/health",
+  "requestContext": {"httpMethod": "GET"}
 }
 EOF
 response=$(cargo lambda invoke --data-file lambda_event.json)
@@ -30,13 +31,15 @@ cat > lambda_event.json <<EOF
 {
   "httpMethod": "POST",
   "path": "/encrypt",
+  "headers": {"content-type": "application/json"},
+  "requestContext": {"httpMethod": "POST"},
   "body": "{\"key\":\"$key\",\"plain_text\":\"$plain_text\"}"
 }
 EOF
 response=$(cargo lambda invoke --data-file lambda_event.json)
 rm lambda_event.json
 
-id=$(echo "$response" | jq -r '.body' | jq -r | jq -e -r '.id') || {
+id=$(echo "$response" | jq -r '.body' | jq -e -r '.data.id') || {
   echo "❌ Failed to extract valid ID"
   exit 1
 }
@@ -47,7 +50,8 @@ echo "▶️ Lambda /decrypt test"
 cat > lambda_event.json <<EOF
 {
   "httpMethod": "GET",
-  "path": "/decrypt/$id/$key"
+  "path": "/decrypt/$id/$key",
+  "requestContext": {"httpMethod": "GET"}
 }
 EOF
 response=$(cargo lambda invoke --data-file lambda_event.json)
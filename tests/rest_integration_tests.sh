@@ -48,4 +48,23 @@ if [[ "$actual_domain" == "$expected_domain"* ]]; then
 else
   echo "❌ Mismatch: expected domain $expected_domain, got $actual_domain"
   exit 1
-fi
\ No newline at end of file
+fi
+
+echo "▶️ Starting POST /decrypt test"
+
+response=$(curl -s -X POST http://localhost:3000/encrypt \
+  -H "Content-Type: application/json" \
+  -d "{\"plain_text\":\"$plain_text\", \"key\":\"$key\"}")
+id=$(echo "$response" | jq -r .data.id)
+
+location=$(curl -s -o /dev/null -w "%{redirect_url}" -X POST \
+  "http://localhost:3000/decrypt/$id" \
+  -H "Content-Type: application/json" \
+  -d "{\"key\":\"$key\"}")
+
+if [[ "$location" == "$plain_text"* ]]; then
+  echo "✅ Redirected to expected URL: $location"
+else
+  echo "❌ Mismatch: expected $plain_text, got $location"
+  exit 1
+fi